@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use lucid::kvstore::KvStore;
+use lucid::kvstore::{KvStore, Limits};
 
 const CIPHER: std::option::Option<[&str; 2]> = Some([
     "123456789012345678901234123456789012345678901234",
@@ -13,14 +13,30 @@ fn set_1_kb_data(c: &mut Criterion) {
     let kv = KvStore::new(CIPHER);
 
     c.bench_function("Set 1KB", |b| {
-        b.iter(|| kv.set("bench_one".to_string(), DATA.to_vec()))
+        b.iter(|| {
+            kv.set(
+                "bench_one".to_string(),
+                vec![DATA.to_vec()],
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap()
+        })
     });
 }
 fn get_1_kb_data(c: &mut Criterion) {
     let kv = KvStore::new(CIPHER);
 
     let k = String::from("bench_one");
-    kv.set(k.clone(), DATA.to_vec());
+    kv.set(
+        k.clone(),
+        vec![DATA.to_vec()],
+        None,
+        None,
+        &Limits::default(),
+    )
+    .unwrap();
 
     c.bench_function("Get 1KB", |b| b.iter(|| kv.get(k.clone())));
 }
@@ -29,14 +45,30 @@ fn set_1_kb_data_without_encryption(c: &mut Criterion) {
     let kv = KvStore::new(None);
 
     c.bench_function("Set 1KB (w/o encrytion)", |b| {
-        b.iter(|| kv.set("bench_one".to_string(), DATA.to_vec()))
+        b.iter(|| {
+            kv.set(
+                "bench_one".to_string(),
+                vec![DATA.to_vec()],
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap()
+        })
     });
 }
 fn get_1_kb_data_without_encryption(c: &mut Criterion) {
     let kv = KvStore::new(None);
 
     let k = String::from("bench_one");
-    kv.set(k.clone(), DATA.to_vec());
+    kv.set(
+        k.clone(),
+        vec![DATA.to_vec()],
+        None,
+        None,
+        &Limits::default(),
+    )
+    .unwrap();
 
     c.bench_function("Get 1KB (w/o encryption)", |b| b.iter(|| kv.get(k.clone())));
 }
@@ -0,0 +1,352 @@
+use std::{collections::HashSet, fs, str::FromStr, sync::RwLock};
+
+use jsonwebtoken::{Algorithm, Validation};
+use pem::Pem;
+use ring::{constant_time, digest};
+use snafu::{ResultExt, Snafu};
+
+use crate::configuration::{Authentication, Claims, Scope};
+use crate::tls::ClientCertificate;
+
+// DER prefixes of a SubjectPublicKeyInfo wrapping an uncompressed EC point
+const EC_P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+const EC_P384_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x76, 0x30, 0x10, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b,
+    0x81, 0x04, 0x00, 0x22, 0x03, 0x62, 0x00,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyFamily {
+    Hmac,
+    Rsa,
+    Ec,
+}
+
+impl KeyFamily {
+    fn of(algorithm: Algorithm) -> KeyFamily {
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => KeyFamily::Hmac,
+            Algorithm::ES256 | Algorithm::ES384 => KeyFamily::Ec,
+            _ => KeyFamily::Rsa,
+        }
+    }
+}
+
+enum KeyMaterial {
+    SecretOrPem(Vec<u8>),
+    RsaComponents { n: String, e: String },
+}
+
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Option<Algorithm>,
+    // None when the family is only known once the PEM is parsed
+    family: Option<KeyFamily>,
+    material: KeyMaterial,
+}
+
+impl VerifyingKey {
+    fn accepts(&self, algorithm: Algorithm, kid: Option<&str>) -> bool {
+        let family = KeyFamily::of(algorithm);
+        let family_matches = match self.family {
+            Some(key_family) => key_family == family,
+            None => family != KeyFamily::Hmac,
+        };
+        let algorithm_matches = match self.algorithm {
+            Some(key_algorithm) => key_algorithm == algorithm,
+            None => true,
+        };
+        let kid_matches = match (kid, &self.kid) {
+            (Some(kid), Some(key_kid)) => kid == key_kid,
+            _ => true,
+        };
+        family_matches && algorithm_matches && kid_matches
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl Jwk {
+    fn into_verifying_key(self) -> Option<VerifyingKey> {
+        if let Some(usage) = &self.usage {
+            if usage != "sig" {
+                return None;
+            }
+        }
+        let algorithm = match &self.alg {
+            Some(alg) => Some(Algorithm::from_str(alg).ok()?),
+            None => None,
+        };
+        match self.kty.as_str() {
+            "RSA" => Some(VerifyingKey {
+                kid: self.kid,
+                algorithm,
+                family: Some(KeyFamily::Rsa),
+                material: KeyMaterial::RsaComponents {
+                    n: self.n?,
+                    e: self.e?,
+                },
+            }),
+            "EC" => {
+                let prefix = match self.crv.as_ref()?.as_str() {
+                    "P-256" => EC_P256_SPKI_PREFIX,
+                    "P-384" => EC_P384_SPKI_PREFIX,
+                    _ => return None,
+                };
+                let mut der = prefix.to_vec();
+                der.push(0x04);
+                der.extend(base64::decode_config(self.x.as_ref()?, base64::URL_SAFE_NO_PAD).ok()?);
+                der.extend(base64::decode_config(self.y.as_ref()?, base64::URL_SAFE_NO_PAD).ok()?);
+                let pem = pem::encode(&Pem {
+                    tag: String::from("PUBLIC KEY"),
+                    contents: der,
+                });
+                Some(VerifyingKey {
+                    kid: self.kid,
+                    algorithm,
+                    family: Some(KeyFamily::Ec),
+                    material: KeyMaterial::SecretOrPem(pem.into_bytes()),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct Keyring {
+    source: Authentication,
+    validation: Validation,
+    keys: Vec<VerifyingKey>,
+}
+
+impl Keyring {
+    pub fn load(config: &Authentication) -> Result<Keyring, Error> {
+        let mut keys = Vec::new();
+        if !config.secret_key.is_empty() {
+            keys.push(VerifyingKey {
+                kid: None,
+                algorithm: None,
+                family: Some(KeyFamily::Hmac),
+                material: KeyMaterial::SecretOrPem(config.secret_key.as_bytes().to_vec()),
+            });
+        }
+        if !config.public_key.is_empty() {
+            keys.push(VerifyingKey {
+                kid: None,
+                algorithm: None,
+                family: None,
+                material: KeyMaterial::SecretOrPem(fs::read(&config.public_key).context(
+                    ReadPublicKey {
+                        path: config.public_key.clone(),
+                    },
+                )?),
+            });
+        }
+        if !config.jwks.is_empty() {
+            let jwks = fs::read(&config.jwks).context(ReadJwks {
+                path: config.jwks.clone(),
+            })?;
+            let jwk_set: JwkSet = serde_json::from_slice(&jwks).context(ParseJwks {
+                path: config.jwks.clone(),
+            })?;
+            for jwk in jwk_set.keys {
+                let kid = jwk.kid.clone().unwrap_or_default();
+                match jwk.into_verifying_key() {
+                    Some(key) => keys.push(key),
+                    None => warn!("Skipping unsupported JWK \"{}\" in {}", kid, config.jwks),
+                }
+            }
+        }
+
+        let mut validation = Validation {
+            algorithms: config.algorithms.clone(),
+            ..Default::default()
+        };
+        if !config.issuer.is_empty() {
+            validation.iss = Some(config.issuer.clone());
+        }
+        if !config.audience.is_empty() {
+            validation.aud = Some(config.audience.iter().cloned().collect::<HashSet<String>>());
+        }
+
+        Ok(Keyring {
+            source: config.clone(),
+            validation,
+            keys,
+        })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let header = jsonwebtoken::decode_header(token).context(InvalidToken)?;
+        if !self.validation.algorithms.contains(&header.alg) {
+            return Err(Error::AlgorithmNotAllowed {
+                algorithm: header.alg,
+            });
+        }
+
+        let mut last_error = None;
+        for key in self
+            .keys
+            .iter()
+            .filter(|key| key.accepts(header.alg, header.kid.as_deref()))
+        {
+            let decoded = match &key.material {
+                KeyMaterial::SecretOrPem(key) => {
+                    jsonwebtoken::decode::<Claims>(token, key, &self.validation)
+                }
+                KeyMaterial::RsaComponents { n, e } => {
+                    jsonwebtoken::decode_rsa_components::<Claims>(token, n, e, &self.validation)
+                }
+            };
+            match decoded {
+                Ok(token_data) => return Ok(token_data.claims),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        match last_error {
+            Some(source) => Err(Error::InvalidToken { source }),
+            None => Err(Error::NoMatchingKey),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl Identity {
+    pub fn anonymous() -> Identity {
+        Identity {
+            name: String::from("anonymous"),
+            scopes: vec![Scope::Admin],
+        }
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+impl From<Claims> for Identity {
+    fn from(claims: Claims) -> Self {
+        // Tokens without a scope claim (such as the root token) keep full access
+        let scopes = match claims.scope {
+            Some(scope) => scope
+                .split_whitespace()
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            None => vec![Scope::Admin],
+        };
+        Identity {
+            name: claims.sub,
+            scopes,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Authenticator {
+    keyring: RwLock<Option<Keyring>>,
+}
+
+impl Authenticator {
+    pub fn new() -> Authenticator {
+        Authenticator::default()
+    }
+
+    pub fn verify(&self, config: &Authentication, token: &str) -> Result<Claims, Error> {
+        if let Some(keyring) = &*self.keyring.read().unwrap() {
+            if keyring.source == *config {
+                return keyring.verify(token);
+            }
+        }
+        let keyring = Keyring::load(config)?;
+        let claims = keyring.verify(token);
+        *self.keyring.write().unwrap() = Some(keyring);
+        claims
+    }
+
+    pub fn verify_api_key(&self, config: &Authentication, key: &str) -> Result<Identity, Error> {
+        let key_hash = digest::digest(&digest::SHA256, key.as_bytes());
+        let mut identity = None;
+        for api_key in &config.api_keys {
+            let expected_hash = hex::decode(&api_key.hash).unwrap_or_default();
+            if constant_time::verify_slices_are_equal(key_hash.as_ref(), &expected_hash).is_ok() {
+                identity = Some(Identity {
+                    name: api_key.name.clone(),
+                    scopes: api_key.scopes.clone(),
+                });
+            }
+        }
+        identity.ok_or(Error::InvalidApiKey)
+    }
+
+    pub fn verify_client_certificate(
+        &self,
+        config: &Authentication,
+        certificate: &ClientCertificate,
+    ) -> Result<Identity, Error> {
+        config
+            .certificates
+            .iter()
+            .find(|mapping| certificate.principals.contains(&mapping.principal))
+            .map(|mapping| Identity {
+                name: mapping.name.clone(),
+                scopes: mapping.scopes.clone(),
+            })
+            .ok_or_else(|| Error::UnmappedCertificate {
+                subject: certificate.subject.clone(),
+            })
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to read the public key {}: {}", path, source))]
+    ReadPublicKey {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Unable to read the JWKS file {}: {}", path, source))]
+    ReadJwks {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Unable to parse the JWKS file {}: {}", path, source))]
+    ParseJwks {
+        path: String,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Invalid JWT token: {}", source))]
+    InvalidToken { source: jsonwebtoken::errors::Error },
+    #[snafu(display("The JWT algorithm {:?} is not allowed.", algorithm))]
+    AlgorithmNotAllowed { algorithm: Algorithm },
+    #[snafu(display("No key matches the JWT token."))]
+    NoMatchingKey,
+    #[snafu(display("Unknown API key."))]
+    InvalidApiKey,
+    #[snafu(display("No identity is mapped to the client certificate {}.", subject))]
+    UnmappedCertificate { subject: String },
+}
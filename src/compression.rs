@@ -0,0 +1,101 @@
+use std::io::{self, Write};
+
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression as Level,
+};
+
+use crate::configuration::CompressionAlgorithm;
+
+// Values are only kept compressed when it actually makes them smaller
+pub fn compress(algorithm: CompressionAlgorithm, level: i32, value: &[u8]) -> Option<Vec<u8>> {
+    let compressed = match algorithm {
+        CompressionAlgorithm::None => return None,
+        CompressionAlgorithm::Zstd => zstd::bulk::compress(value, level).ok()?,
+        CompressionAlgorithm::Lz4 => lz4_flex::compress(value),
+    };
+    Some(compressed).filter(|compressed| compressed.len() < value.len())
+}
+
+// `size` is the size of the value before it was compressed
+pub fn decompress(
+    algorithm: CompressionAlgorithm,
+    data: &[u8],
+    size: usize,
+) -> io::Result<Vec<u8>> {
+    match algorithm {
+        CompressionAlgorithm::None => Ok(data.to_vec()),
+        CompressionAlgorithm::Zstd => zstd::bulk::decompress(data, size),
+        CompressionAlgorithm::Lz4 => lz4_flex::decompress(data, size)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    // Picks the encoding with the highest quality in an Accept-Encoding header,
+    // ties are broken in favor of zstd, then gzip
+    pub fn negotiate(accept_encoding: &str) -> Option<ContentEncoding> {
+        let accepted: Vec<(String, f32)> = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let name = params.next()?.trim().to_ascii_lowercase();
+                let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                    Some(quality) => quality.trim().parse::<f32>().ok()?,
+                    None => 1.0,
+                };
+                Some((name, quality))
+            })
+            .collect();
+        let quality = |name: &str| {
+            accepted
+                .iter()
+                .find(|(accepted, _)| accepted == name)
+                .or_else(|| accepted.iter().find(|(accepted, _)| accepted == "*"))
+                .map_or(0.0, |(_, quality)| *quality)
+        };
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for encoding in &[
+            ContentEncoding::Zstd,
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+        ] {
+            let quality = quality(encoding.name());
+            if quality > best.map_or(0.0, |(_, best)| best) {
+                best = Some((*encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    pub fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Zstd => zstd::bulk::compress(data, 0),
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
@@ -1,19 +1,25 @@
 use std::{
+    collections::HashMap,
+    fs::File,
     net::{IpAddr, Ipv4Addr},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use app_dirs::{AppDataType, AppDirsError, AppInfo};
+use jsonwebtoken::Algorithm;
 use log::LevelFilter;
 use rand::Rng;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
 
 const APP_INFO: AppInfo = AppInfo {
     name: "lucid",
     author: "LucidKV",
 };
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Configuration {
     pub general: General,
     pub authentication: Authentication,
@@ -24,6 +30,14 @@ pub struct Configuration {
     pub store: Store,
     pub http: Http,
     pub logging: Logging,
+    pub metrics: Metrics,
+    pub health: Health,
+    pub rate_limit: RateLimit,
+    pub quotas: Quotas,
+    pub locks: Locks,
+    pub namespaces: HashMap<String, NamespaceSettings>,
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
 }
 
 impl Configuration {
@@ -32,10 +46,158 @@ impl Configuration {
         path.push("lucid.yml");
         Ok(path)
     }
+
+    pub fn load(path: &Path) -> Result<Configuration, Error> {
+        let mut configuration: Configuration =
+            serde_yaml::from_reader(File::open(path).context(OpenConfigFile)?)
+                .context(ReadConfigFile)?;
+        configuration.source = Some(path.to_path_buf());
+        Ok(configuration)
+    }
+
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut configuration = serde_json::to_value(self).unwrap_or_default();
+        for pointer in &[
+            "/authentication/root_token",
+            "/authentication/secret_key",
+            "/encryption/private_key",
+            "/encryption/iv",
+        ] {
+            redact(configuration.pointer_mut(pointer));
+        }
+        if let Some(api_keys) = configuration
+            .pointer_mut("/authentication/api_keys")
+            .and_then(|api_keys| api_keys.as_array_mut())
+        {
+            for api_key in api_keys {
+                redact(api_key.get_mut("hash"));
+            }
+        }
+        configuration
+    }
+
+    // Applies the settings that can change while running, the others are only reported
+    pub fn reload(&mut self, new: Configuration) -> ReloadReport {
+        let mut report = ReloadReport::default();
+        report.reloaded.extend(changed_settings(
+            "authentication",
+            &self.authentication,
+            &new.authentication,
+        ));
+        report
+            .reloaded
+            .extend(changed_settings("sse", &self.sse, &new.sse));
+        report
+            .reloaded
+            .extend(changed_settings("webui", &self.webui, &new.webui));
+        // Stores are created with their compression and history settings
+        let (restart_required, reloaded): (Vec<String>, Vec<String>) =
+            changed_settings("store", &self.store, &new.store)
+                .into_iter()
+                .partition(|setting| setting == "store.compression" || setting == "store.history");
+        report.reloaded.extend(reloaded);
+        report.restart_required.extend(restart_required);
+        report
+            .reloaded
+            .extend(changed_settings("http", &self.http, &new.http));
+        report
+            .reloaded
+            .extend(changed_settings("metrics", &self.metrics, &new.metrics));
+        report
+            .reloaded
+            .extend(changed_settings("health", &self.health, &new.health));
+        report.reloaded.extend(changed_settings(
+            "rate_limit",
+            &self.rate_limit,
+            &new.rate_limit,
+        ));
+        report
+            .reloaded
+            .extend(changed_settings("quotas", &self.quotas, &new.quotas));
+        report
+            .reloaded
+            .extend(changed_settings("locks", &self.locks, &new.locks));
+        if self.logging.level != new.logging.level {
+            report.reloaded.push(String::from("logging.level"));
+        }
+        report.restart_required.extend(changed_settings(
+            "namespaces",
+            &self.namespaces,
+            &new.namespaces,
+        ));
+        report.restart_required.extend(changed_settings(
+            "general",
+            &self.general,
+            &new.general,
+        ));
+        report.restart_required.extend(changed_settings(
+            "persistence",
+            &self.persistence,
+            &new.persistence,
+        ));
+        // Unset keys are randomly generated on every load, they only matter when enabled
+        if self.encryption.enabled || new.encryption.enabled {
+            report.restart_required.extend(changed_settings(
+                "encryption",
+                &self.encryption,
+                &new.encryption,
+            ));
+        }
+        report.restart_required.extend(changed_settings(
+            "logging.outputs",
+            &self.logging.outputs,
+            &new.logging.outputs,
+        ));
+
+        self.authentication = new.authentication;
+        self.sse = new.sse;
+        self.webui = new.webui;
+        self.store = Store {
+            compression: self.store.compression.clone(),
+            history: self.store.history.clone(),
+            ..new.store
+        };
+        self.http = new.http;
+        self.metrics = new.metrics;
+        self.health = new.health;
+        self.rate_limit = new.rate_limit;
+        self.quotas = new.quotas;
+        self.locks = new.locks;
+        self.logging.level = new.logging.level;
+        report
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadReport {
+    pub reloaded: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+fn redact(value: Option<&mut serde_json::Value>) {
+    if let Some(value) = value {
+        if value.as_str().filter(|secret| !secret.is_empty()).is_some() {
+            *value = serde_json::Value::from("<redacted>");
+        }
+    }
+}
+
+fn changed_settings<T: Serialize>(section: &str, old: &T, new: &T) -> Vec<String> {
+    let old = serde_json::to_value(old).unwrap_or_default();
+    let new = serde_json::to_value(new).unwrap_or_default();
+    match (old.as_object(), new.as_object()) {
+        (Some(old), Some(new)) => new
+            .iter()
+            .filter(|(name, value)| old.get(*name) != Some(value))
+            .map(|(name, _)| format!("{}.{}", section, name))
+            .collect(),
+        _ if old != new => vec![String::from(section)],
+        _ => Vec::new(),
+    }
 }
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct General {
     pub bind_address: IpAddr,
     pub port: u16,
@@ -43,6 +205,14 @@ pub struct General {
     pub use_ssl: bool,
     pub ssl_certificate: String,
     pub ssl_certificate_key: String,
+    pub ssl_client_ca: String,
+    pub ssl_client_auth_required: bool,
+    pub ssl_reload_interval: u64,
+    pub http_alongside_ssl: bool,
+    pub redirect_http_to_ssl: bool,
+    pub unix_socket: String,
+    pub unix_socket_mode: String,
+    pub drain_timeout: u64,
     pub show_banner: bool,
 }
 
@@ -55,17 +225,32 @@ impl Default for General {
             use_ssl: false,
             ssl_certificate: String::new(),
             ssl_certificate_key: String::new(),
+            ssl_client_ca: String::new(),
+            ssl_client_auth_required: false,
+            ssl_reload_interval: 60,
+            http_alongside_ssl: false,
+            redirect_http_to_ssl: false,
+            unix_socket: String::new(),
+            unix_socket_mode: String::from("660"),
+            drain_timeout: 30,
             show_banner: true,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
-#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Authentication {
     pub enabled: bool,
     pub root_token: String,
     pub secret_key: String,
+    pub algorithms: Vec<Algorithm>,
+    pub public_key: String,
+    pub jwks: String,
+    pub issuer: String,
+    pub audience: Vec<String>,
+    pub api_keys: Vec<ApiKey>,
+    pub certificates: Vec<CertificateMapping>,
 }
 
 impl Default for Authentication {
@@ -74,12 +259,56 @@ impl Default for Authentication {
             enabled: false,
             root_token: String::new(),
             secret_key: String::new(),
+            algorithms: vec![Algorithm::HS256],
+            public_key: String::new(),
+            jwks: String::new(),
+            issuer: String::new(),
+            audience: Vec::new(),
+            api_keys: Vec::new(),
+            certificates: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertificateMapping {
+    // Matched against the common names and subject alternative names of the
+    // certificate, such as "billing" or "spiffe://acme/billing", not its subject DN
+    pub principal: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(()),
         }
     }
 }
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Persistence {
     pub enabled: bool,
     pub location: String,
@@ -94,8 +323,8 @@ impl Default for Persistence {
     }
 }
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Encryption {
     pub enabled: bool,
     pub private_key: String,
@@ -112,8 +341,8 @@ impl Default for Encryption {
     }
 }
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ServerSentEvent {
     pub enabled: bool,
 }
@@ -124,8 +353,8 @@ impl Default for ServerSentEvent {
     }
 }
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct WebUI {
     pub enabled: bool,
 }
@@ -136,34 +365,92 @@ impl Default for WebUI {
     }
 }
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Store {
     pub max_limit: u64,
+    pub default_ttl: u64,
+    pub compression: Compression,
+    pub history: History,
+    // Seconds deleted keys are kept in the trash for, 0 deletes them at once
+    pub trash_retention: u64,
+    // Longest a blocking pop can wait for a value, in seconds
+    pub max_pop_timeout: u64,
+    // Most items a list, hash, set or sorted set can hold, 0 for no limit
+    pub max_collection_len: u64,
 }
 
 impl Default for Store {
     fn default() -> Self {
-        Self { max_limit: 7340032 }
+        Self {
+            max_limit: 7340032,
+            default_ttl: 0,
+            compression: Compression::default(),
+            history: History::default(),
+            trash_retention: 0,
+            max_pop_timeout: 300,
+            max_collection_len: 0,
+        }
     }
 }
 
+// Previous values are kept while within both limits, 0 disables a limit.
+// History is disabled when both are 0.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
+pub struct History {
+    pub max_versions: u64,
+    // In seconds, since the value was replaced
+    pub max_age: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    // Values smaller than this are stored as is
+    pub threshold: u64,
+    // Only used by zstd
+    pub level: i32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::None,
+            threshold: 1024,
+            level: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    None,
+    Zstd,
+    Lz4,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Http {
+    // Compresses responses for clients sending an Accept-Encoding header
+    pub compression: bool,
     pub request_size_limit: u64,
 }
 
 impl Default for Http {
     fn default() -> Self {
         Self {
+            compression: false,
             request_size_limit: 8388608,
         }
     }
 }
 
-#[serde(default)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Logging {
     pub level: LevelFilter,
     pub outputs: Vec<LogOutput>,
@@ -178,6 +465,145 @@ impl Default for Logging {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metrics {
+    pub enabled: bool,
+    pub require_auth: bool,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            require_auth: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Health {
+    pub memory_threshold: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub enabled: bool,
+    pub read: Limit,
+    pub write: Limit,
+    pub admin: Limit,
+}
+
+impl RateLimit {
+    pub fn limit(&self, scope: Scope) -> &Limit {
+        match scope {
+            Scope::Read => &self.read,
+            Scope::Write => &self.write,
+            Scope::Admin => &self.admin,
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            read: Limit {
+                rate: 100.0,
+                burst: 200.0,
+            },
+            write: Limit {
+                rate: 50.0,
+                burst: 100.0,
+            },
+            admin: Limit {
+                rate: 1.0,
+                burst: 10.0,
+            },
+        }
+    }
+}
+
+// Requests per second refilling a bucket holding at most `burst` requests, a zero rate disables it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    pub fn capacity(&self) -> f64 {
+        self.burst.max(1.0)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quotas {
+    pub max_bytes: u64,
+    pub subjects: HashMap<String, u64>,
+}
+
+impl Quotas {
+    pub fn max_bytes_for(&self, subject: &str) -> u64 {
+        self.subjects
+            .get(subject)
+            .cloned()
+            .unwrap_or(self.max_bytes)
+    }
+}
+
+// All durations are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Locks {
+    pub default_ttl: u64,
+    pub max_ttl: u64,
+    // Longest time an acquire request can wait in the queue
+    pub max_wait: u64,
+}
+
+impl Default for Locks {
+    fn default() -> Self {
+        Self {
+            default_ttl: 30,
+            max_ttl: 3600,
+            max_wait: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NamespaceSettings {
+    pub max_limit: u64,
+    pub max_bytes: u64,
+    pub eviction: EvictionPolicy,
+    pub default_ttl: u64,
+    pub encryption: bool,
+}
+
+impl Default for NamespaceSettings {
+    fn default() -> Self {
+        Self {
+            max_limit: 0,
+            max_bytes: 0,
+            eviction: EvictionPolicy::NoEviction,
+            default_ttl: 0,
+            encryption: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    NoEviction,
+    Oldest,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase", tag = "type")]
 pub enum LogOutput {
@@ -192,4 +618,14 @@ pub struct Claims {
     pub iss: String,
     pub iat: i64,
     pub exp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to open the Lucid configuration file: {}", source))]
+    OpenConfigFile { source: std::io::Error },
+    #[snafu(display("Unable to read the Lucid configuration file: {}", source))]
+    ReadConfigFile { source: serde_yaml::Error },
 }
@@ -0,0 +1,200 @@
+use serde_json::{Map, Value};
+use snafu::{ResultExt, Snafu};
+
+// RFC 6902 operations, paths are JSON Pointers
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+pub fn parse_patch(body: &[u8]) -> Result<Vec<Operation>, Error> {
+    serde_json::from_slice(body).context(InvalidPatch)
+}
+
+pub fn parse_merge_patch(body: &[u8]) -> Result<Value, Error> {
+    serde_json::from_slice(body).context(InvalidPatch)
+}
+
+// Operations are applied on a copy, so the document is left untouched if one fails
+pub fn apply_patch(document: &mut Value, operations: &[Operation]) -> Result<(), Error> {
+    let mut patched = document.clone();
+    for operation in operations {
+        match operation {
+            Operation::Add { path, value } => add(&mut patched, path, value.clone())?,
+            Operation::Remove { path } => {
+                remove(&mut patched, path)?;
+            }
+            Operation::Replace { path, value } => match patched.pointer_mut(path) {
+                Some(target) => *target = value.clone(),
+                None => return Err(path_not_found(path)),
+            },
+            Operation::Move { from, path } => {
+                if path.starts_with(&format!("{}/", from)) {
+                    return Err(Error::InvalidPath { path: path.clone() });
+                }
+                let value = remove(&mut patched, from)?;
+                add(&mut patched, path, value)?;
+            }
+            Operation::Copy { from, path } => {
+                let value = patched
+                    .pointer(from)
+                    .cloned()
+                    .ok_or_else(|| path_not_found(from))?;
+                add(&mut patched, path, value)?;
+            }
+            Operation::Test { path, value } => {
+                if patched.pointer(path) != Some(value) {
+                    return Err(Error::TestFailed { path: path.clone() });
+                }
+            }
+        }
+    }
+    *document = patched;
+    Ok(())
+}
+
+// RFC 7396, null values remove members
+pub fn merge_patch(document: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !document.is_object() {
+                *document = Value::Object(Map::new());
+            }
+            let members = document.as_object_mut().unwrap();
+            for (name, value) in patch {
+                if value.is_null() {
+                    members.remove(name);
+                } else {
+                    merge_patch(members.entry(name.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        patch => *document = patch.clone(),
+    }
+}
+
+// Accepts JSON Pointers and the simple JSONPath forms $.a.b, $.a[0] and $['a']
+pub fn select<'a>(document: &'a Value, path: &str) -> Result<Option<&'a Value>, Error> {
+    if path.is_empty() || path.starts_with('/') {
+        return Ok(document.pointer(path));
+    }
+    let pointer: String = json_path_tokens(path)?
+        .iter()
+        .map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1")))
+        .collect();
+    Ok(document.pointer(&pointer))
+}
+
+fn json_path_tokens(path: &str) -> Result<Vec<String>, Error> {
+    let invalid = || Error::InvalidPath {
+        path: path.to_string(),
+    };
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut tokens = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            tokens.push(after[..end].to_string());
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            let token = after[..end].trim();
+            let quoted = token.len() >= 2
+                && ((token.starts_with('\'') && token.ends_with('\''))
+                    || (token.starts_with('"') && token.ends_with('"')));
+            if quoted {
+                tokens.push(token[1..token.len() - 1].to_string());
+            } else if !token.is_empty() && token.chars().all(|c| c.is_ascii_digit()) {
+                tokens.push(token.to_string());
+            } else {
+                return Err(invalid());
+            }
+            rest = &after[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(tokens)
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), Error> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(());
+    }
+    let (parent, name) = split_pointer(path)?;
+    match document.pointer_mut(parent) {
+        Some(Value::Object(members)) => {
+            members.insert(name, value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = match name.as_str() {
+                "-" => items.len(),
+                name => array_index(name, items.len() + 1).ok_or_else(|| path_not_found(path))?,
+            };
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(path_not_found(path)),
+    }
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, Error> {
+    let (parent, name) = split_pointer(path)?;
+    match document.pointer_mut(parent) {
+        Some(Value::Object(members)) => members.remove(&name).ok_or_else(|| path_not_found(path)),
+        Some(Value::Array(items)) => match array_index(&name, items.len()) {
+            Some(index) => Ok(items.remove(index)),
+            None => Err(path_not_found(path)),
+        },
+        _ => Err(path_not_found(path)),
+    }
+}
+
+// Splits a pointer into its parent pointer and its unescaped last token
+fn split_pointer(path: &str) -> Result<(&str, String), Error> {
+    match path.rfind('/') {
+        Some(index) if path.starts_with('/') => Ok((
+            &path[..index],
+            path[index + 1..].replace("~1", "/").replace("~0", "~"),
+        )),
+        _ => Err(Error::InvalidPath {
+            path: path.to_string(),
+        }),
+    }
+}
+
+fn array_index(token: &str, len: usize) -> Option<usize> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) {
+        return None;
+    }
+    token.parse::<usize>().ok().filter(|index| *index < len)
+}
+
+fn path_not_found(path: &str) -> Error {
+    Error::PathNotFound {
+        path: path.to_string(),
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid JSON patch: {}", source))]
+    InvalidPatch { source: serde_json::Error },
+    #[snafu(display("Invalid path \"{}\".", path))]
+    InvalidPath { path: String },
+    #[snafu(display("The path \"{}\" does not exist in the document.", path))]
+    PathNotFound { path: String },
+    #[snafu(display("The value at \"{}\" does not match the test operation.", path))]
+    TestFailed { path: String },
+}
@@ -1,31 +1,359 @@
-use block_modes::block_padding::ZeroPadding;
-use block_modes::{BlockMode, Cbc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use block_cipher_trait::{generic_array::GenericArray, BlockCipher};
+use bytes::{Bytes, BytesMut};
 use chashmap::CHashMap;
 use chrono::{DateTime, Duration, Utc};
 
+use serde::{de::DeserializeOwned, Serialize};
 use serpent::Serpent;
+use snafu::Snafu;
+
+use crate::compression;
+use crate::configuration::{Compression, CompressionAlgorithm, History};
+use crate::document;
+use crate::metrics;
+
+lazy_static! {
+    // Held while a write is checked and accounted, so concurrent writes cannot
+    // all pass the limits and overshoot them together
+    static ref ADMISSION: Mutex<()> = Mutex::new(());
+}
+
+// Size of the chunks values are stored and read by, so large values are never
+// copied or decrypted at once
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// A value stored as chunks of at most CHUNK_SIZE bytes, all of them full but the
+// last one. Chunks can share an allocation.
+#[derive(Debug, Clone, Default)]
+pub struct Data {
+    chunks: Vec<Bytes>,
+    len: usize,
+}
+
+impl Data {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn chunks(&self) -> &[Bytes] {
+        &self.chunks
+    }
+
+    // Only copied when the range spans several chunks
+    pub fn slice(&self, range: Range<usize>) -> Bytes {
+        let mut parts = Vec::new();
+        let mut offset = 0;
+        for chunk in &self.chunks {
+            let end = offset + chunk.len();
+            if end > range.start && offset < range.end {
+                let start = range.start.saturating_sub(offset);
+                parts.push(chunk.slice(start..(range.end - offset).min(chunk.len())));
+            }
+            if end >= range.end {
+                break;
+            }
+            offset = end;
+        }
+        match parts.len() {
+            0 => Bytes::new(),
+            1 => parts.remove(0),
+            _ => {
+                let mut data = BytesMut::with_capacity(range.len());
+                for part in parts {
+                    data.extend_from_slice(&part);
+                }
+                data.freeze()
+            }
+        }
+    }
 
-type SerpentCbc = Cbc<Serpent, ZeroPadding>;
+    pub fn to_bytes(&self) -> Bytes {
+        self.slice(0..self.len)
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.chunks.concat()
+    }
+
+    fn bytes(&self) -> impl Iterator<Item = &u8> {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+
+    // Fills the last chunk first, only the bytes of that chunk are copied
+    pub fn push(&mut self, mut data: Bytes) {
+        if let Some(last) = self.chunks.last_mut() {
+            let take = CHUNK_SIZE.saturating_sub(last.len()).min(data.len());
+            if take > 0 {
+                let mut filled = BytesMut::with_capacity(last.len() + take);
+                filled.extend_from_slice(last);
+                filled.extend_from_slice(&data.split_to(take));
+                *last = filled.freeze();
+                self.len += take;
+            }
+        }
+        while !data.is_empty() {
+            let chunk = data.split_to(CHUNK_SIZE.min(data.len()));
+            self.len += chunk.len();
+            self.chunks.push(chunk);
+        }
+    }
+}
+
+impl From<Bytes> for Data {
+    fn from(bytes: Bytes) -> Self {
+        let mut data = Data::default();
+        data.push(bytes);
+        data
+    }
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(value: Vec<u8>) -> Self {
+        Data::from(Bytes::from(value))
+    }
+}
+
+impl PartialEq<[u8]> for Data {
+    fn eq(&self, other: &[u8]) -> bool {
+        let mut rest = other;
+        self.len == other.len()
+            && self.chunks.iter().all(|chunk| {
+                let (head, tail) = rest.split_at(chunk.len());
+                rest = tail;
+                chunk[..] == *head
+            })
+    }
+}
+
+impl PartialEq<&[u8]> for Data {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<Vec<u8>> for Data {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self == other.as_slice()
+    }
+}
+
+impl PartialEq for Data {
+    fn eq(&self, other: &Data) -> bool {
+        self.len == other.len && self.bytes().eq(other.bytes())
+    }
+}
+
+// Typed values keep their content serialized as JSON in `data`, so they are
+// encrypted and accounted like any other value
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    Bytes,
+    List,
+    Hash,
+    Set,
+    SortedSet,
+}
+
+// Scores by member, ordered by score when read
+type SortedSet = BTreeMap<String, f64>;
+
+// Collections are stored as JSON: every read decodes the whole collection and
+// every update rewrites it, so each operation is O(n) in the collection size and
+// sorted set ranges also sort it by score. They are bounded by the value size
+// limit like other values, and by the number of items a write can grow them to.
+
+trait Collection: Serialize + DeserializeOwned + Default {
+    const DATA_TYPE: DataType;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Collection for VecDeque<String> {
+    const DATA_TYPE: DataType = DataType::List;
+    fn len(&self) -> usize {
+        VecDeque::len(self)
+    }
+}
+
+impl Collection for BTreeMap<String, String> {
+    const DATA_TYPE: DataType = DataType::Hash;
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+}
+
+impl Collection for BTreeSet<String> {
+    const DATA_TYPE: DataType = DataType::Set;
+    fn len(&self) -> usize {
+        BTreeSet::len(self)
+    }
+}
+
+impl Collection for SortedSet {
+    const DATA_TYPE: DataType = DataType::SortedSet;
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+}
+
+// Vets a new value before it is stored, given the value it replaces
+pub type Check<'a> = dyn Fn(&KvElement, Option<&KvElement>) -> Result<(), Error> + Sync + 'a;
+
+// Bounds a write that can create or grow a value
+pub struct Limits<'a> {
+    // Charged for the values created by the write
+    pub owner: Option<&'a str>,
+    pub check: &'a Check<'a>,
+    // Most items a write can grow a collection to, 0 for no limit
+    pub max_collection_len: u64,
+}
+
+impl Default for Limits<'_> {
+    fn default() -> Self {
+        Limits {
+            owner: None,
+            check: &|_, _| Ok(()),
+            max_collection_len: 0,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct KvElement {
-    pub data: Vec<u8>,
+    // Shared with the readers of the value, cloning an element does not copy it
+    pub data: Data,
+    // Size of the value before compression, the same as the stored size when uncompressed
+    pub size: usize,
+    pub compression: CompressionAlgorithm,
+    // Makes the keystream of each encrypted value unique, renewed on every rewrite
+    pub nonce: u64,
+    pub data_type: DataType,
     pub mime_type: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
-    pub expire_at: DateTime<Utc>,
+    pub expire_at: Option<DateTime<Utc>>,
     pub update_count: i32,
     pub locked: bool,
+    pub owner: Option<String>,
+}
+
+impl KvElement {
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expire_at, Some(expire_at) if expire_at <= Utc::now())
+    }
+}
+
+// A replaced value, still sealed as it was stored. Its number is the update
+// count of the key when it was written.
+#[derive(Debug, Clone)]
+struct Version {
+    pub kv_element: KvElement,
+    pub replaced_at: DateTime<Utc>,
+}
+
+// A deleted value, still sealed, kept until it is restored or purged
+#[derive(Debug, Clone)]
+pub struct Tombstone {
+    pub kv_element: KvElement,
+    pub deleted_at: DateTime<Utc>,
+    pub purge_at: DateTime<Utc>,
 }
 
 pub struct KvStore {
     container: CHashMap<String, KvElement>,
-    cipher: Option<Cipher>,
+    trash: CHashMap<String, Tombstone>,
+    // Oldest versions first
+    history: CHashMap<String, VecDeque<Version>>,
+    history_settings: History,
+    cipher: Option<Arc<Cipher>>,
+    compression: Compression,
+    bytes_by_mime: Mutex<HashMap<String, u64>>,
+    bytes_by_owner: Mutex<HashMap<String, u64>>,
+    compression_stats: Mutex<CompressionStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreStats {
+    pub keys: usize,
+    pub bytes_by_mime: HashMap<String, u64>,
+    pub compression: CompressionStats,
+}
+
+// Only counts the values stored compressed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompressionStats {
+    pub values: u64,
+    pub stored_bytes: u64,
+    pub original_bytes: u64,
+}
+
+impl CompressionStats {
+    pub fn ratio(&self) -> Option<f64> {
+        match self.stored_bytes {
+            0 => None,
+            stored_bytes => Some(self.original_bytes as f64 / stored_bytes as f64),
+        }
+    }
+
+    pub fn add(&mut self, other: &CompressionStats) {
+        self.values += other.values;
+        self.stored_bytes += other.stored_bytes;
+        self.original_bytes += other.original_bytes;
+    }
+}
+
+impl StoreStats {
+    pub fn stored_bytes(&self) -> u64 {
+        self.bytes_by_mime.values().sum()
+    }
+
+    // Values plus the fixed size of each entry, heap allocated keys and MIME types are not counted
+    pub fn memory_estimate(&self) -> u64 {
+        self.stored_bytes() + (self.keys * std::mem::size_of::<(String, KvElement)>()) as u64
+    }
 }
 
 pub struct Cipher {
-    priv_key: [u8; 24],
-    iv: [u8; 16],
+    serpent: Serpent,
+    iv: u128,
+}
+
+impl Cipher {
+    // Serpent in CTR mode, so any byte range can be encrypted or decrypted on its
+    // own. The counter block mixes the IV, the value nonce and the block index.
+    // A nonce must never encrypt two contents at the same offset. Values get a
+    // fresh nonce whenever they are sealed and nothing is ever encrypted under an
+    // existing one, since older versions of the value share it.
+    fn apply_keystream(&self, nonce: u64, offset: usize, data: &mut [u8]) {
+        let mut position = offset;
+        let mut done = 0;
+        while done < data.len() {
+            let counter = self.iv ^ (u128::from(nonce) << 64) ^ (position / 16) as u128;
+            let mut block = GenericArray::clone_from_slice(&counter.to_be_bytes());
+            self.serpent.encrypt_block(&mut block);
+            let skip = position % 16;
+            let len = (16 - skip).min(data.len() - done);
+            for (byte, key) in data[done..done + len].iter_mut().zip(&block[skip..]) {
+                *byte ^= key;
+            }
+            done += len;
+            position += len;
+        }
+    }
 }
 
 impl KvStore {
@@ -33,70 +361,216 @@ impl KvStore {
         // TODO: prepare looped persistence
         let mut kv = KvStore {
             container: CHashMap::new(),
+            trash: CHashMap::new(),
+            history: CHashMap::new(),
+            history_settings: History::default(),
             cipher: None,
+            compression: Compression::default(),
+            bytes_by_mime: Mutex::new(HashMap::new()),
+            bytes_by_owner: Mutex::new(HashMap::new()),
+            compression_stats: Mutex::new(CompressionStats::default()),
         };
 
         if let Some(c) = cipher {
-            let (mut priv_key, mut iv) = ([0u8; 24], [0u8; 16]);
-            priv_key[..24].copy_from_slice(&hex::decode(c[0]).unwrap());
+            let mut iv = [0u8; 16];
             iv[..16].copy_from_slice(&hex::decode(c[1]).unwrap());
-            kv.cipher = Some(Cipher { priv_key, iv });
+            kv.cipher = Some(Arc::new(Cipher {
+                serpent: Serpent::new_varkey(&hex::decode(c[0]).unwrap()).unwrap(),
+                iv: u128::from_be_bytes(iv),
+            }));
         }
 
         kv
     }
 
-    pub fn set(&self, key: String, mut value: Vec<u8>, mime: Option<String>) -> Option<KvElement> {
+    // Values are compressed before being encrypted, from the given size threshold
+    pub fn with_compression(mut self, compression: Compression) -> KvStore {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_history(mut self, history: History) -> KvStore {
+        self.history_settings = history;
+        self
+    }
+
+    // The owner is charged for the stored bytes until someone else overwrites the key.
+    // Values and their expiration are replaced together, None removes any previous TTL.
+    // The chunks are stored as they are when the value is neither compressed nor
+    // encrypted, the mime type is guessed from the first one.
+    pub fn set(
+        &self,
+        key: String,
+        value: Vec<Vec<u8>>,
+        mime: Option<String>,
+        expire_at: Option<DateTime<Utc>>,
+        limits: &Limits,
+    ) -> Result<Option<KvElement>, Error> {
         // TODO: prepare iterative persistence
-        if let Some(c) = &self.cipher {
-            let cipher = SerpentCbc::new_var(&c.priv_key, &c.iv).unwrap();
-            value = cipher.encrypt_vec(&value);
-        }
         let mime_type = match mime {
             Some(gived_mimetype) => gived_mimetype,
-            None => tree_magic::from_u8(value.as_ref()).to_string(),
+            None => tree_magic::from_u8(value.first().map_or(&[][..], Vec::as_slice)).to_string(),
         };
+        let owner = limits.owner.map(String::from);
+        self.remove_expired(&key);
         match &mut self.container.get_mut(&key) {
             Some(kv_element) => {
                 if !kv_element.locked {
-                    kv_element.data = value;
-                    kv_element.mime_type = mime_type;
+                    let mut updated = KvElement {
+                        data_type: DataType::Bytes,
+                        mime_type,
+                        owner,
+                        expire_at,
+                        ..(**kv_element).clone()
+                    };
+                    self.seal(&mut updated, value);
+                    self.admit(limits, &updated, Some(kv_element))?;
+                    self.record(&key, std::mem::replace(&mut **kv_element, updated));
                 }
                 kv_element.updated_at = Utc::now();
                 kv_element.update_count = kv_element.update_count + 1;
-                Some(kv_element.to_owned())
+                Ok(Some(kv_element.to_owned()))
             }
             None => {
-                let kv_element = KvElement {
-                    data: value,
+                let mut kv_element = KvElement {
+                    data: Data::default(),
+                    size: 0,
+                    compression: CompressionAlgorithm::None,
+                    nonce: 0,
+                    data_type: DataType::Bytes,
                     mime_type,
                     created_at: Utc::now(),
                     updated_at: Utc::now(),
-                    expire_at: Utc::now(),
+                    expire_at,
                     update_count: 1,
                     locked: false,
+                    owner,
                 };
-                self.container.insert(key, kv_element)
+                self.seal(&mut kv_element, value);
+                self.admit(limits, &kv_element, None)?;
+                let previous = self.container.insert(key, kv_element);
+                if let Some(previous) = &previous {
+                    self.account(previous, false);
+                }
+                Ok(previous)
             }
         }
     }
 
     pub fn get(&self, key: String) -> Option<KvElement> {
+        self.remove_expired(&key);
         match self.container.get(&key) {
             Some(value) => {
                 let mut cloned_value = value.clone();
-
-                if let Some(c) = &self.cipher {
-                    let cipher = SerpentCbc::new_var(&c.priv_key, &c.iv).unwrap();
-                    cloned_value.data = cipher.decrypt_vec(&value.data).unwrap();
-                }
+                cloned_value.data = self.open(&value);
                 Some(cloned_value)
             }
             None => None,
         }
     }
 
+    // Only the requested bytes are decrypted. `range` gets the length of the
+    // value and returns None when the range cannot be satisfied.
+    pub fn get_range(
+        &self,
+        key: String,
+        range: impl FnOnce(usize) -> Option<Range<usize>>,
+    ) -> Option<(KvElement, usize, Option<Range<usize>>)> {
+        self.remove_expired(&key);
+        let kv_element = self.container.get(&key)?;
+        let len = kv_element.size;
+        let range = range(len).filter(|range| range.start < range.end && range.end <= len);
+        let mut partial = KvElement {
+            data: Data::default(),
+            ..kv_element.clone()
+        };
+        if let Some(range) = &range {
+            partial.data = Data::from(match kv_element.compression {
+                CompressionAlgorithm::None => {
+                    read(self.cipher.as_deref(), &kv_element, range.clone())
+                }
+                _ => self.open(&kv_element).slice(range.clone()),
+            });
+        }
+        Some((partial, len, range))
+    }
+
+    // The returned element has no data, its content is read from the chunks
+    pub fn get_chunks(&self, key: String) -> Option<(KvElement, Chunks)> {
+        self.remove_expired(&key);
+        let kv_element = self.container.get(&key)?;
+        // Compressed values can only be read as a whole
+        let chunks = match kv_element.compression {
+            CompressionAlgorithm::None => Chunks::new(kv_element.clone(), self.cipher.clone()),
+            _ => Chunks::new(
+                KvElement {
+                    data: self.open(&kv_element),
+                    compression: CompressionAlgorithm::None,
+                    ..kv_element.clone()
+                },
+                None,
+            ),
+        };
+        let value = KvElement {
+            data: Data::default(),
+            ..kv_element.clone()
+        };
+        Some((value, chunks))
+    }
+
+    // Plain values only get the appended bytes, compressed or encrypted ones are
+    // sealed again as a whole
+    pub fn append(&self, key: String, value: &[u8], limits: &Limits) -> Result<usize, Error> {
+        self.remove_expired(&key);
+        let mut result = Ok(value.len());
+        self.container.alter(key.clone(), |kv_element| {
+            let kv_element = match kv_element {
+                Some(kv_element) => kv_element,
+                None => {
+                    let mime_type = tree_magic::from_u8(value).to_string();
+                    let kv_element = KvElement {
+                        owner: limits.owner.map(String::from),
+                        ..self.new_element(value.to_vec(), DataType::Bytes, mime_type)
+                    };
+                    if let Err(e) = self.admit(limits, &kv_element, None) {
+                        result = Err(e);
+                        return None;
+                    }
+                    return Some(kv_element);
+                }
+            };
+            if kv_element.locked {
+                result = Err(Error::Locked);
+                return Some(kv_element);
+            } else if kv_element.data_type != DataType::Bytes {
+                result = Err(Error::WrongType);
+                return Some(kv_element);
+            }
+            let mut updated = kv_element.clone();
+            if kv_element.compression != CompressionAlgorithm::None || self.cipher.is_some() {
+                self.seal(
+                    &mut updated,
+                    vec![self.open(&kv_element).to_vec(), value.to_vec()],
+                );
+            } else {
+                updated.data.push(Bytes::copy_from_slice(value));
+                updated.size = updated.data.len();
+            }
+            if let Err(e) = self.admit(limits, &updated, Some(&kv_element)) {
+                result = Err(e);
+                return Some(kv_element);
+            }
+            self.record(&key, kv_element);
+            updated.updated_at = Utc::now();
+            updated.update_count += 1;
+            result = Ok(updated.size);
+            Some(updated)
+        });
+        result
+    }
+
     pub fn switch_lock(&self, key: String, to_lock: bool) -> bool {
+        self.remove_expired(&key);
         match &mut self.container.get_mut(&key) {
             Some(kv_element) => {
                 if kv_element.locked == to_lock {
@@ -109,29 +583,303 @@ impl KvStore {
         }
     }
 
-    pub fn increment_or_decrement(&self, key: String, value: f64) -> bool {
+    // Counters are stored as decimal strings. A missing key is created with
+    // `initial`, without applying the delta, or reported as not found.
+    pub fn increment(
+        &self,
+        key: String,
+        delta: i64,
+        initial: Option<i64>,
+        limits: &Limits,
+    ) -> Result<i64, Error> {
+        self.remove_expired(&key);
+        let mut result = Err(Error::KeyNotFound);
+        self.container.alter(key.clone(), |kv_element| match kv_element {
+            Some(kv_element) => match self.add_to_counter(&kv_element, delta, limits) {
+                Ok((updated, value)) => {
+                    result = Ok(value);
+                    self.record(&key, kv_element);
+                    Some(updated)
+                }
+                Err(e) => {
+                    result = Err(e);
+                    Some(kv_element)
+                }
+            },
+            None => initial.and_then(|initial| {
+                let kv_element = KvElement {
+                    owner: limits.owner.map(String::from),
+                    ..self.new_element(
+                        initial.to_string().into_bytes(),
+                        DataType::Bytes,
+                        "text/plain".to_string(),
+                    )
+                };
+                result = self.admit(limits, &kv_element, None).map(|_| initial);
+                Some(kv_element).filter(|_| result.is_ok())
+            }),
+        });
+        result
+    }
+
+    // Lists follow the Redis semantics, missing keys are empty lists and
+    // emptied lists are removed
+    pub fn push(
+        &self,
+        key: String,
+        values: Vec<String>,
+        front: bool,
+        limits: &Limits,
+    ) -> Result<usize, Error> {
+        self.update_collection(key, Some(limits), |list: &mut VecDeque<String>| {
+            for value in values {
+                if front {
+                    list.push_front(value);
+                } else {
+                    list.push_back(value);
+                }
+            }
+            Ok(list.len())
+        })
+    }
+
+    pub fn pop(&self, key: String, front: bool) -> Result<String, Error> {
+        self.update_collection(key, None, |list: &mut VecDeque<String>| {
+            if front {
+                Ok(list.pop_front())
+            } else {
+                Ok(list.pop_back())
+            }
+        })?
+        .ok_or(Error::KeyNotFound)
+    }
+
+    // Indexes are inclusive and negative ones count from the end of the list
+    pub fn range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>, Error> {
+        let list: VecDeque<String> = self.get_collection(key)?;
+        let bounds = range_bounds(list.len(), start, stop);
+        Ok(list
+            .into_iter()
+            .skip(bounds.start)
+            .take(bounds.len())
+            .collect())
+    }
+
+    pub fn trim(&self, key: String, start: i64, stop: i64) -> Result<usize, Error> {
+        self.update_collection(key, None, |list: &mut VecDeque<String>| {
+            let bounds = range_bounds(list.len(), start, stop);
+            list.truncate(bounds.end);
+            list.drain(..bounds.start);
+            Ok(list.len())
+        })
+    }
+
+    // Returns the number of fields that were added rather than replaced
+    pub fn hset(
+        &self,
+        key: String,
+        fields: BTreeMap<String, String>,
+        limits: &Limits,
+    ) -> Result<usize, Error> {
+        self.update_collection(key, Some(limits), |hash: &mut BTreeMap<String, String>| {
+            let mut added = 0;
+            for (field, value) in fields {
+                if hash.insert(field, value).is_none() {
+                    added += 1;
+                }
+            }
+            Ok(added)
+        })
+    }
+
+    pub fn hget(&self, key: String, field: &str) -> Result<String, Error> {
+        let mut hash: BTreeMap<String, String> = self.get_collection(key)?;
+        hash.remove(field).ok_or(Error::FieldNotFound)
+    }
+
+    // Returns the number of fields that were removed, emptied hashes are removed
+    pub fn hdel(&self, key: String, fields: &[String]) -> Result<usize, Error> {
+        self.update_collection(key, None, |hash: &mut BTreeMap<String, String>| {
+            Ok(fields
+                .iter()
+                .filter(|field| hash.remove(*field).is_some())
+                .count())
+        })
+    }
+
+    // Missing fields count from zero
+    pub fn hincrby(
+        &self,
+        key: String,
+        field: String,
+        delta: i64,
+        limits: &Limits,
+    ) -> Result<i64, Error> {
+        self.update_collection(key, Some(limits), |hash: &mut BTreeMap<String, String>| {
+            let current = match hash.get(&field) {
+                Some(value) => value.trim().parse::<i64>().map_err(|_| Error::NotAnInteger)?,
+                None => 0,
+            };
+            let value = current.checked_add(delta).ok_or(Error::Overflow)?;
+            hash.insert(field, value.to_string());
+            Ok(value)
+        })
+    }
+
+    pub fn sadd(&self, key: String, members: Vec<String>, limits: &Limits) -> Result<usize, Error> {
+        self.update_collection(key, Some(limits), |set: &mut BTreeSet<String>| {
+            let len = set.len();
+            set.extend(members);
+            Ok(set.len() - len)
+        })
+    }
+
+    pub fn srem(&self, key: String, members: &[String]) -> Result<usize, Error> {
+        self.update_collection(key, None, |set: &mut BTreeSet<String>| {
+            Ok(members.iter().filter(|member| set.remove(*member)).count())
+        })
+    }
+
+    pub fn sismember(&self, key: String, member: &str) -> Result<bool, Error> {
+        match self.get_collection::<BTreeSet<String>>(key) {
+            Ok(set) => Ok(set.contains(member)),
+            Err(Error::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Missing keys are empty sets, so they empty the intersection
+    pub fn sinter(&self, keys: &[String]) -> Result<BTreeSet<String>, Error> {
+        let mut intersection: Option<BTreeSet<String>> = None;
+        for key in keys {
+            let set = match self.get_collection::<BTreeSet<String>>(key.clone()) {
+                Ok(set) => set,
+                Err(Error::KeyNotFound) => BTreeSet::new(),
+                Err(e) => return Err(e),
+            };
+            intersection = Some(match intersection {
+                Some(intersection) => intersection.intersection(&set).cloned().collect(),
+                None => set,
+            });
+        }
+        Ok(intersection.unwrap_or_default())
+    }
+
+    // Returns the number of members that were added rather than updated
+    pub fn zadd(
+        &self,
+        key: String,
+        scores: BTreeMap<String, f64>,
+        limits: &Limits,
+    ) -> Result<usize, Error> {
+        if scores.values().any(|score| !score.is_finite()) {
+            return Err(Error::NotANumber);
+        }
+        self.update_collection(key, Some(limits), |set: &mut SortedSet| {
+            let mut added = 0;
+            for (member, score) in scores {
+                if set.insert(member, score).is_none() {
+                    added += 1;
+                }
+            }
+            Ok(added)
+        })
+    }
+
+    pub fn zincrby(
+        &self,
+        key: String,
+        member: String,
+        delta: f64,
+        limits: &Limits,
+    ) -> Result<f64, Error> {
+        self.update_collection(key, Some(limits), |set: &mut SortedSet| {
+            let score = set.get(&member).unwrap_or(&0.0) + delta;
+            if !score.is_finite() {
+                return Err(Error::NotANumber);
+            }
+            set.insert(member, score);
+            Ok(score)
+        })
+    }
+
+    pub fn zrem(&self, key: String, members: &[String]) -> Result<usize, Error> {
+        self.update_collection(key, None, |set: &mut SortedSet| {
+            Ok(members
+                .iter()
+                .filter(|member| set.remove(*member).is_some())
+                .count())
+        })
+    }
+
+    // Ranks are inclusive and negative ones count from the highest score
+    pub fn zrange(&self, key: String, start: i64, stop: i64) -> Result<Vec<(String, f64)>, Error> {
+        let ranked = ranked(self.get_collection(key)?);
+        let bounds = range_bounds(ranked.len(), start, stop);
+        Ok(ranked
+            .into_iter()
+            .skip(bounds.start)
+            .take(bounds.len())
+            .collect())
+    }
+
+    pub fn zrange_by_score(
+        &self,
+        key: String,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<(String, f64)>, Error> {
+        Ok(ranked(self.get_collection(key)?)
+            .into_iter()
+            .filter(|(_, score)| *score >= min && *score <= max)
+            .collect())
+    }
+
+    // Changes a JSON value in place, the stored value has to parse as JSON
+    pub fn update_json(
+        &self,
+        key: String,
+        limits: &Limits,
+        f: impl FnOnce(&mut serde_json::Value) -> Result<(), document::Error>,
+    ) -> Result<serde_json::Value, Error> {
+        self.remove_expired(&key);
         match &mut self.container.get_mut(&key) {
             Some(kv_element) => {
-                let byte_to_string = String::from_utf8(kv_element.clone().data).unwrap(); // TODO: handle convert to string error
-                match byte_to_string.trim().parse::<f64>() {
-                    Ok(initial_value) => {
-                        kv_element.data = (initial_value + value).to_string().into_bytes();
-                        kv_element.updated_at = Utc::now();
-                        kv_element.update_count = kv_element.update_count + 1;
-                        true
-                    }
-                    Err(_) => false,
+                if kv_element.locked {
+                    return Err(Error::Locked);
                 }
+                if kv_element.data_type != DataType::Bytes {
+                    return Err(Error::WrongType);
+                }
+                let mut value: serde_json::Value =
+                    serde_json::from_slice(&self.open(kv_element).to_bytes())
+                        .map_err(|_| Error::NotJson)?;
+                f(&mut value).map_err(|source| Error::Patch { source })?;
+                let mut updated = (**kv_element).clone();
+                self.seal(&mut updated, vec![serde_json::to_vec(&value).unwrap()]);
+                self.admit(limits, &updated, Some(kv_element))?;
+                updated.updated_at = Utc::now();
+                updated.update_count += 1;
+                self.record(&key, std::mem::replace(&mut **kv_element, updated));
+                Ok(value)
             }
-            None => false,
+            None => Err(Error::KeyNotFound),
         }
     }
 
+    pub fn data_type(&self, key: &str) -> Option<DataType> {
+        self.remove_expired(key);
+        self.container
+            .get(key)
+            .map(|kv_element| kv_element.data_type)
+    }
+
     pub fn set_expiration(&self, key: String, ttl: i64) -> Option<DateTime<Utc>> {
+        self.remove_expired(&key);
         match &mut self.container.get_mut(&key) {
             Some(kv_element) => {
                 let expiration_date = Utc::now() + Duration::seconds(ttl);
-                kv_element.expire_at = expiration_date;
+                kv_element.expire_at = Some(expiration_date);
                 kv_element.updated_at = Utc::now();
                 kv_element.update_count = kv_element.update_count + 1;
                 Some(expiration_date)
@@ -140,7 +888,667 @@ impl KvStore {
         }
     }
 
-    pub fn drop(&self, key: String) {
-        self.container.remove(&key);
+    pub fn drop(&self, key: String) -> bool {
+        self.remove_expired(&key);
+        self.history.remove(&key);
+        match self.container.remove(&key) {
+            Some(kv_element) => {
+                self.account(&kv_element, false);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The value is kept for `retention` and replaces any previous tombstone of the key
+    pub fn trash(&self, key: String, retention: Duration) -> bool {
+        self.remove_expired(&key);
+        let kv_element = match self.container.remove(&key) {
+            Some(kv_element) => kv_element,
+            None => return false,
+        };
+        self.account(&kv_element, false);
+        self.history.remove(&key);
+        self.purge_expired();
+        let deleted_at = Utc::now();
+        let tombstone = Tombstone {
+            kv_element,
+            deleted_at,
+            purge_at: deleted_at + retention,
+        };
+        self.trash.insert(key, tombstone);
+        true
+    }
+
+    pub fn trash_prefix(&self, prefix: &str, retention: Duration) -> Vec<String> {
+        self.keys_with_prefix(prefix)
+            .into_iter()
+            .filter(|key| self.trash(key.clone(), retention))
+            .collect()
+    }
+
+    // Sorted by key, without data
+    pub fn trashed(&self, prefix: &str) -> Vec<(String, Tombstone)> {
+        self.purge_expired();
+        let tombstones = RefCell::new(Vec::new());
+        self.trash.retain(|key, tombstone| {
+            if key.starts_with(prefix) {
+                let mut tombstone = tombstone.clone();
+                tombstone.kv_element.data = Data::default();
+                tombstones.borrow_mut().push((key.clone(), tombstone));
+            }
+            true
+        });
+        let mut tombstones = tombstones.into_inner();
+        tombstones.sort_by(|a, b| a.0.cmp(&b.0));
+        tombstones
+    }
+
+    // A key created again since it was deleted is not overwritten. Trashed values
+    // are not counted, so they are checked again as they come back.
+    pub fn undelete(&self, key: String, limits: &Limits) -> Result<(), Error> {
+        self.remove_expired(&key);
+        let tombstone = self
+            .trash
+            .remove(&key)
+            .filter(|tombstone| tombstone.purge_at > Utc::now())
+            .ok_or(Error::KeyNotFound)?;
+        let mut result = Ok(());
+        let mut rejected = None;
+        self.container.alter(key.clone(), |kv_element| match kv_element {
+            Some(kv_element) => {
+                result = Err(Error::KeyExists);
+                rejected = Some(tombstone);
+                Some(kv_element)
+            }
+            None => match self.admit(limits, &tombstone.kv_element, None) {
+                Ok(()) => Some(tombstone.kv_element),
+                Err(e) => {
+                    result = Err(e);
+                    rejected = Some(tombstone);
+                    None
+                }
+            },
+        });
+        if let Some(tombstone) = rejected {
+            self.trash.insert(key, tombstone);
+        }
+        result
+    }
+
+    pub fn purge(&self, key: &str) -> bool {
+        self.trash
+            .remove(key)
+            .filter(|tombstone| tombstone.purge_at > Utc::now())
+            .is_some()
+    }
+
+    pub fn purge_prefix(&self, prefix: &str) -> Vec<String> {
+        self.trashed(prefix)
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| self.purge(key))
+            .collect()
+    }
+
+    // The current version first, without data
+    pub fn history(&self, key: String) -> Result<Vec<KvElement>, Error> {
+        self.remove_expired(&key);
+        let current = self.container.get(&key).ok_or(Error::KeyNotFound)?.clone();
+        let mut versions = vec![current];
+        self.history.alter(key, |history| {
+            let mut history = history?;
+            self.prune(&mut history);
+            versions.extend(history.iter().rev().map(|version| version.kv_element.clone()));
+            Some(history).filter(|history| !history.is_empty())
+        });
+        for version in &mut versions {
+            version.data = Data::default();
+        }
+        Ok(versions)
+    }
+
+    pub fn get_version(&self, key: String, number: i32) -> Result<KvElement, Error> {
+        self.remove_expired(&key);
+        let current = self.container.get(&key).ok_or(Error::KeyNotFound)?.clone();
+        let mut kv_element = match current.update_count == number {
+            true => current,
+            false => self.find_version(&key, number)?,
+        };
+        kv_element.data = self.open(&kv_element);
+        Ok(kv_element)
+    }
+
+    // The restored value becomes a new version, the replaced one is kept in the history
+    pub fn restore(&self, key: String, number: i32) -> Result<i32, Error> {
+        self.remove_expired(&key);
+        let current = self.container.get(&key).ok_or(Error::KeyNotFound)?.update_count;
+        if current == number {
+            return Ok(current);
+        }
+        let version = self.find_version(&key, number)?;
+        let mut result = Err(Error::KeyNotFound);
+        self.container.alter(key.clone(), |kv_element| {
+            let mut kv_element = kv_element?;
+            if kv_element.locked {
+                result = Err(Error::Locked);
+                return Some(kv_element);
+            }
+            self.record(&key, kv_element.clone());
+            self.account(&kv_element, false);
+            // Sealed again, so it does not share a nonce with the version
+            self.seal(&mut kv_element, vec![self.open(&version).to_vec()]);
+            kv_element.data_type = version.data_type;
+            kv_element.mime_type = version.mime_type;
+            self.account(&kv_element, true);
+            kv_element.updated_at = Utc::now();
+            kv_element.update_count += 1;
+            result = Ok(kv_element.update_count);
+            Some(kv_element)
+        });
+        result
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            keys: self.container.len(),
+            bytes_by_mime: self.bytes_by_mime.lock().unwrap().clone(),
+            compression: *self.compression_stats.lock().unwrap(),
+        }
+    }
+
+    pub fn stored_bytes(&self) -> u64 {
+        self.bytes_by_mime.lock().unwrap().values().sum()
+    }
+
+    pub fn owner_bytes(&self, owner: &str) -> u64 {
+        self.bytes_by_owner
+            .lock()
+            .unwrap()
+            .get(owner)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    // Stored size and owner of a value, without decrypting it
+    pub fn entry_size(&self, key: &str) -> Option<(u64, Option<String>)> {
+        self.remove_expired(key);
+        self.container
+            .get(key)
+            .map(|kv_element| (kv_element.data.len() as u64, kv_element.owner.clone()))
+    }
+
+    // Drops the least recently updated unlocked keys until `needed` more bytes fit in `max_bytes`
+    pub fn evict(&self, needed: u64, max_bytes: u64) -> usize {
+        if self.stored_bytes() + needed <= max_bytes {
+            return 0;
+        }
+        let mut candidates = Vec::new();
+        self.for_each(|key, kv_element| {
+            if !kv_element.locked {
+                candidates.push((kv_element.updated_at, key.clone()));
+            }
+        });
+        candidates.sort();
+        let mut evicted = 0;
+        for (_, key) in candidates {
+            if self.stored_bytes() + needed <= max_bytes {
+                break;
+            }
+            self.drop(key);
+            evicted += 1;
+        }
+        metrics::EVICTED_KEYS.inc_by(evicted as i64);
+        evicted
+    }
+
+    // Visits every entry, only one bucket of the map is locked at a time
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let (mut keys, mut expired) = (Vec::new(), Vec::new());
+        self.for_each(|key, kv_element| {
+            if kv_element.is_expired() {
+                expired.push(key.clone());
+            } else if key.starts_with(prefix) {
+                keys.push(key.clone());
+            }
+        });
+        for key in expired {
+            self.remove_expired(&key);
+        }
+        keys.sort();
+        keys
+    }
+    pub fn drop_prefix(&self, prefix: &str) -> Vec<String> {
+        let mut dropped = Vec::new();
+        for key in self.keys_with_prefix(prefix) {
+            if let Some(kv_element) = self.container.remove(&key) {
+                self.account(&kv_element, false);
+                self.history.remove(&key);
+                dropped.push(key);
+            }
+        }
+        dropped
     }
+    fn get_collection<T: Collection>(&self, key: String) -> Result<T, Error> {
+        let kv_element = self.get(key).ok_or(Error::KeyNotFound)?;
+        if kv_element.data_type != T::DATA_TYPE {
+            return Err(Error::WrongType);
+        }
+        serde_json::from_slice(&kv_element.data.to_bytes()).map_err(|_| Error::WrongType)
+    }
+
+    // Only writes given limits can create or grow a collection. The value is
+    // left untouched when `f` fails.
+    fn update_collection<T: Collection, R>(
+        &self,
+        key: String,
+        limits: Option<&Limits>,
+        f: impl FnOnce(&mut T) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.remove_expired(&key);
+        let mut result = Err(Error::KeyNotFound);
+        self.container.alter(key.clone(), |kv_element| {
+            let (kv_element, created) = match kv_element {
+                Some(kv_element) if kv_element.locked => {
+                    result = Err(Error::Locked);
+                    return Some(kv_element);
+                }
+                Some(kv_element) if kv_element.data_type != T::DATA_TYPE => {
+                    result = Err(Error::WrongType);
+                    return Some(kv_element);
+                }
+                Some(kv_element) => (kv_element, false),
+                // Counted as an update below
+                None if limits.is_some() => (
+                    KvElement {
+                        update_count: 0,
+                        owner: limits.and_then(|limits| limits.owner).map(String::from),
+                        ..self.new_element(Vec::new(), T::DATA_TYPE, "application/json".to_string())
+                    },
+                    true,
+                ),
+                None => return None,
+            };
+            let mut collection: T = if kv_element.data.is_empty() {
+                T::default()
+            } else {
+                match serde_json::from_slice(&self.open(&kv_element).to_bytes()) {
+                    Ok(collection) => collection,
+                    Err(_) => {
+                        result = Err(Error::WrongType);
+                        return Some(kv_element);
+                    }
+                }
+            };
+            let len = collection.len();
+            let value = match f(&mut collection) {
+                Ok(value) => value,
+                Err(e) => {
+                    result = Err(e);
+                    return Some(kv_element).filter(|_| !created);
+                }
+            };
+            if let Some(max_len) = limits
+                .map(|limits| limits.max_collection_len)
+                .filter(|&max_len| max_len > 0 && collection.len() > len.max(max_len as usize))
+            {
+                result = Err(Error::CollectionTooLarge { max_len });
+                return Some(kv_element).filter(|_| !created);
+            }
+            if collection.is_empty() {
+                result = Ok(value);
+                self.account(&kv_element, false);
+                self.history.remove(&key);
+                return None;
+            }
+            let mut updated = kv_element.clone();
+            self.seal(&mut updated, vec![serde_json::to_vec(&collection).unwrap()]);
+            let replaced = Some(&kv_element).filter(|_| !created);
+            let admitted = match limits {
+                Some(limits) => self.admit(limits, &updated, replaced),
+                None => {
+                    self.account(&kv_element, false);
+                    self.account(&updated, true);
+                    Ok(())
+                }
+            };
+            if let Err(e) = admitted {
+                result = Err(e);
+                return Some(kv_element).filter(|_| !created);
+            }
+            result = Ok(value);
+            if !created {
+                self.record(&key, kv_element);
+            }
+            updated.updated_at = Utc::now();
+            updated.update_count += 1;
+            Some(updated)
+        });
+        result
+    }
+    fn add_to_counter(
+        &self,
+        kv_element: &KvElement,
+        delta: i64,
+        limits: &Limits,
+    ) -> Result<(KvElement, i64), Error> {
+        if kv_element.locked {
+            return Err(Error::Locked);
+        }
+        if kv_element.data_type != DataType::Bytes {
+            return Err(Error::WrongType);
+        }
+        let current = std::str::from_utf8(&self.open(kv_element).to_bytes())
+            .ok()
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .ok_or(Error::NotAnInteger)?;
+        let value = current.checked_add(delta).ok_or(Error::Overflow)?;
+        let mut updated = kv_element.clone();
+        self.seal(&mut updated, vec![value.to_string().into_bytes()]);
+        self.admit(limits, &updated, Some(kv_element))?;
+        updated.updated_at = Utc::now();
+        updated.update_count += 1;
+        Ok((updated, value))
+    }
+    fn new_element(&self, value: Vec<u8>, data_type: DataType, mime_type: String) -> KvElement {
+        let mut kv_element = KvElement {
+            data: Data::default(),
+            size: 0,
+            compression: CompressionAlgorithm::None,
+            nonce: 0,
+            data_type,
+            mime_type,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            expire_at: None,
+            update_count: 1,
+            locked: false,
+            owner: None,
+        };
+        self.seal(&mut kv_element, vec![value]);
+        kv_element
+    }
+    // Checks a write against its limits and accounts it, the value it replaces
+    // is no longer counted
+    fn admit(
+        &self,
+        limits: &Limits,
+        updated: &KvElement,
+        replaced: Option<&KvElement>,
+    ) -> Result<(), Error> {
+        let _admission = ADMISSION.lock().unwrap();
+        (limits.check)(updated, replaced)?;
+        if let Some(replaced) = replaced {
+            self.account(replaced, false);
+        }
+        self.account(updated, true);
+        Ok(())
+    }
+    // Encrypts a new content for the value, with a fresh nonce. The parts are
+    // encrypted in place and kept as chunks, only compression joins them.
+    fn seal(&self, kv_element: &mut KvElement, mut value: Vec<Vec<u8>>) {
+        kv_element.size = value.iter().map(Vec::len).sum();
+        kv_element.compression = CompressionAlgorithm::None;
+        let algorithm = self.compression.algorithm;
+        if algorithm != CompressionAlgorithm::None
+            && kv_element.size as u64 >= self.compression.threshold
+        {
+            if let Some(compressed) =
+                compression::compress(algorithm, self.compression.level, &value.concat())
+            {
+                value = vec![compressed];
+                kv_element.compression = algorithm;
+            }
+        }
+        kv_element.nonce = rand::random();
+        kv_element.data = Data::default();
+        for mut part in value {
+            let offset = kv_element.data.len();
+            crypt(
+                self.cipher.as_deref(),
+                kv_element.nonce,
+                offset,
+                &mut part,
+                "encrypt",
+            );
+            kv_element.data.push(Bytes::from(part));
+        }
+    }
+    fn open(&self, kv_element: &KvElement) -> Data {
+        match kv_element.compression {
+            CompressionAlgorithm::None => {
+                let mut data = Data::default();
+                for chunk in Chunks::new(kv_element.clone(), self.cipher.clone()) {
+                    data.push(chunk);
+                }
+                data
+            }
+            algorithm => {
+                let data = read(self.cipher.as_deref(), kv_element, 0..kv_element.data.len());
+                compression::decompress(algorithm, &data, kv_element.size)
+                    .map(Data::from)
+                    .expect("Unable to decompress a stored value")
+            }
+        }
+    }
+    // Keys expire lazily, the next time they are accessed
+    fn remove_expired(&self, key: &str) {
+        let mut expired = None;
+        self.container
+            .alter(key.to_string(), |kv_element| match kv_element {
+                Some(kv_element) if kv_element.is_expired() => {
+                    expired = Some(kv_element);
+                    None
+                }
+                kv_element => kv_element,
+            });
+        if let Some(kv_element) = expired {
+            self.account(&kv_element, false);
+            self.history.remove(key);
+            metrics::EXPIRED_KEYS.inc();
+        }
+    }
+    fn purge_expired(&self) {
+        let now = Utc::now();
+        self.trash.retain(|_, tombstone| tombstone.purge_at > now);
+    }
+    fn record(&self, key: &str, kv_element: KvElement) {
+        let settings = &self.history_settings;
+        if settings.max_versions == 0 && settings.max_age == 0 {
+            return;
+        }
+        let version = Version {
+            kv_element,
+            replaced_at: Utc::now(),
+        };
+        self.history.alter(key.to_string(), |history| {
+            let mut history = history.unwrap_or_default();
+            history.push_back(version);
+            self.prune(&mut history);
+            Some(history).filter(|history| !history.is_empty())
+        });
+    }
+    fn prune(&self, history: &mut VecDeque<Version>) {
+        let settings = &self.history_settings;
+        if settings.max_versions > 0 {
+            while history.len() as u64 > settings.max_versions {
+                history.pop_front();
+            }
+        }
+        if settings.max_age > 0 {
+            let oldest = Utc::now() - Duration::seconds(settings.max_age as i64);
+            while matches!(history.front(), Some(version) if version.replaced_at < oldest) {
+                history.pop_front();
+            }
+        }
+    }
+    fn find_version(&self, key: &str, number: i32) -> Result<KvElement, Error> {
+        let mut found = None;
+        self.history.alter(key.to_string(), |history| {
+            let mut history = history?;
+            self.prune(&mut history);
+            found = history
+                .iter()
+                .find(|version| version.kv_element.update_count == number)
+                .map(|version| version.kv_element.clone());
+            Some(history).filter(|history| !history.is_empty())
+        });
+        found.ok_or(Error::VersionNotFound)
+    }
+    fn for_each<F: FnMut(&String, &KvElement)>(&self, f: F) {
+        let f = RefCell::new(f);
+        self.container.retain(|key, kv_element| {
+            (f.borrow_mut())(key, kv_element);
+            true
+        });
+    }
+
+    fn account(&self, kv_element: &KvElement, added: bool) {
+        let len = kv_element.data.len() as u64;
+        adjust(&self.bytes_by_mime, &kv_element.mime_type, len, added);
+        if let Some(owner) = &kv_element.owner {
+            adjust(&self.bytes_by_owner, owner, len, added);
+        }
+        if kv_element.compression != CompressionAlgorithm::None {
+            let mut stats = self.compression_stats.lock().unwrap();
+            let size = kv_element.size as u64;
+            if added {
+                stats.values += 1;
+                stats.stored_bytes += len;
+                stats.original_bytes += size;
+            } else {
+                stats.values = stats.values.saturating_sub(1);
+                stats.stored_bytes = stats.stored_bytes.saturating_sub(len);
+                stats.original_bytes = stats.original_bytes.saturating_sub(size);
+            }
+        }
+    }
+}
+
+// Iterates over a value by its stored chunks, each one is only decrypted when it is reached
+pub struct Chunks {
+    kv_element: KvElement,
+    cipher: Option<Arc<Cipher>>,
+    index: usize,
+    offset: usize,
+}
+
+impl Chunks {
+    fn new(kv_element: KvElement, cipher: Option<Arc<Cipher>>) -> Self {
+        Chunks {
+            kv_element,
+            cipher,
+            index: 0,
+            offset: 0,
+        }
+    }
+
+    // Size of the whole value, in bytes
+    pub fn size(&self) -> usize {
+        self.kv_element.data.len()
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        let chunk = self.kv_element.data.chunks().get(self.index)?.clone();
+        let offset = self.offset;
+        self.index += 1;
+        self.offset += chunk.len();
+        Some(match self.cipher.as_deref() {
+            None => chunk,
+            cipher => {
+                let mut data = chunk.to_vec();
+                crypt(cipher, self.kv_element.nonce, offset, &mut data, "decrypt");
+                Bytes::from(data)
+            }
+        })
+    }
+}
+
+// Unencrypted values are shared, only encrypted ones are copied to be decrypted
+fn read(cipher: Option<&Cipher>, kv_element: &KvElement, range: Range<usize>) -> Bytes {
+    match cipher {
+        None => kv_element.data.slice(range),
+        Some(_) => {
+            let offset = range.start;
+            let mut data = kv_element.data.slice(range).to_vec();
+            crypt(cipher, kv_element.nonce, offset, &mut data, "decrypt");
+            Bytes::from(data)
+        }
+    }
+}
+
+fn crypt(cipher: Option<&Cipher>, nonce: u64, offset: usize, data: &mut [u8], operation: &str) {
+    if let Some(cipher) = cipher {
+        metrics::ENCRYPTION_DURATION
+            .with_label_values(&[operation])
+            .observe_closure_duration(|| cipher.apply_keystream(nonce, offset, data));
+    }
+}
+
+fn ranked(set: SortedSet) -> Vec<(String, f64)> {
+    let mut ranked: Vec<(String, f64)> = set.into_iter().collect();
+    ranked.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+    ranked
+}
+
+fn range_bounds(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        0..0
+    } else {
+        start as usize..stop as usize + 1
+    }
+}
+
+fn adjust(totals: &Mutex<HashMap<String, u64>>, name: &str, len: u64, added: bool) {
+    let mut totals = totals.lock().unwrap();
+    let total = totals.entry(name.to_string()).or_insert(0);
+    if added {
+        *total += len;
+    } else {
+        *total = total.saturating_sub(len);
+    }
+    if *total == 0 {
+        totals.remove(name);
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("The specified key does not exist."))]
+    KeyNotFound,
+    #[snafu(display("The specified key is not a valid integer value."))]
+    NotAnInteger,
+    #[snafu(display("The counter would overflow a 64-bit signed integer."))]
+    Overflow,
+    #[snafu(display("The specified key cannot be updated, it is currently locked."))]
+    Locked,
+    #[snafu(display("The specified key holds another type of value."))]
+    WrongType,
+    #[snafu(display("The specified field does not exist."))]
+    FieldNotFound,
+    #[snafu(display("Scores must be finite numbers."))]
+    NotANumber,
+    #[snafu(display("The specified key does not hold a valid JSON value."))]
+    NotJson,
+    #[snafu(display("{}", source))]
+    Patch { source: document::Error },
+    #[snafu(display("The specified version does not exist."))]
+    VersionNotFound,
+    #[snafu(display("The specified key already exists."))]
+    KeyExists,
+    #[snafu(display("The maximum allowed value size is {} bytes.", max_limit))]
+    ValueTooLarge { max_limit: u64 },
+    #[snafu(display("A collection can hold at most {} items.", max_len))]
+    CollectionTooLarge { max_len: u64 },
+    #[snafu(display("The storage quota of {} bytes is exceeded.", max_bytes))]
+    QuotaExceeded { max_bytes: u64 },
+    #[snafu(display("The namespace is full, it holds at most {} bytes.", max_bytes))]
+    NamespaceFull { max_bytes: u64 },
 }
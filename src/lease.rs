@@ -0,0 +1,194 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Duration, Utc};
+use ring::constant_time;
+use snafu::Snafu;
+use tokio::sync::oneshot;
+
+#[derive(Debug, Clone)]
+pub struct Lease {
+    // Proves the ownership of the lock, only given to its owner
+    pub token: String,
+    // Increases with every acquisition, so the writes of a stale owner can be told apart
+    pub fencing_token: u64,
+    pub expire_at: DateTime<Utc>,
+}
+
+impl Lease {
+    fn is_expired(&self) -> bool {
+        self.expire_at <= Utc::now()
+    }
+
+    // Compared in constant time, like API keys
+    fn is_owned_by(&self, token: &str) -> bool {
+        constant_time::verify_slices_are_equal(self.token.as_bytes(), token.as_bytes()).is_ok()
+    }
+}
+
+struct Waiter {
+    id: u64,
+    ttl: Duration,
+    sender: oneshot::Sender<Lease>,
+}
+
+#[derive(Default)]
+struct LockState {
+    lease: Option<Lease>,
+    waiters: VecDeque<Waiter>,
+}
+
+pub enum Acquire {
+    Acquired(Lease),
+    // The lease is sent through the receiver once the waiters before are served
+    Queued {
+        id: u64,
+        receiver: oneshot::Receiver<Lease>,
+    },
+}
+
+#[derive(Default)]
+pub struct Leases {
+    locks: Mutex<HashMap<String, LockState>>,
+    fencing_tokens: AtomicU64,
+    waiter_ids: AtomicU64,
+}
+
+impl Leases {
+    pub fn new() -> Leases {
+        Leases::default()
+    }
+
+    pub fn acquire(&self, name: &str, ttl: Duration) -> Result<Lease, Error> {
+        self.with_lock(name, |state| match &state.lease {
+            Some(lease) => Err(Error::Held {
+                expire_at: lease.expire_at,
+            }),
+            None => {
+                let lease = self.grant(ttl);
+                state.lease = Some(lease.clone());
+                Ok(lease)
+            }
+        })
+    }
+
+    // Waiters are served in order, as leases are released or expire
+    pub fn acquire_or_queue(&self, name: &str, ttl: Duration) -> Acquire {
+        self.with_lock(name, |state| {
+            if state.lease.is_none() {
+                let lease = self.grant(ttl);
+                state.lease = Some(lease.clone());
+                return Acquire::Acquired(lease);
+            }
+            let (sender, receiver) = oneshot::channel();
+            let id = self.waiter_ids.fetch_add(1, Ordering::Relaxed);
+            state.waiters.push_back(Waiter { id, ttl, sender });
+            Acquire::Queued { id, receiver }
+        })
+    }
+
+    // Returns false when the waiter is no longer queued, the lease was then already sent
+    pub fn cancel(&self, name: &str, id: u64) -> bool {
+        self.with_lock(name, |state| {
+            let len = state.waiters.len();
+            state.waiters.retain(|waiter| waiter.id != id);
+            state.waiters.len() < len
+        })
+    }
+
+    pub fn renew(&self, name: &str, token: &str, ttl: Duration) -> Result<Lease, Error> {
+        self.with_lock(name, |state| match &mut state.lease {
+            Some(lease) if lease.is_owned_by(token) => {
+                lease.expire_at = Utc::now() + ttl;
+                Ok(lease.clone())
+            }
+            Some(_) => Err(Error::NotOwner),
+            None => Err(Error::NotHeld),
+        })
+    }
+
+    pub fn release(&self, name: &str, token: &str) -> Result<(), Error> {
+        self.with_lock(name, |state| match &state.lease {
+            Some(lease) if lease.is_owned_by(token) => {
+                state.lease = None;
+                Ok(())
+            }
+            Some(_) => Err(Error::NotOwner),
+            None => Err(Error::NotHeld),
+        })
+    }
+
+    // A fencing token is only valid while its lease is held, so the writes of an
+    // owner whose lease expired or was released are refused
+    pub fn check_fencing_token(&self, name: &str, fencing_token: u64) -> Result<(), Error> {
+        self.with_lock(name, |state| match &state.lease {
+            Some(lease) if lease.fencing_token == fencing_token => Ok(()),
+            _ => Err(Error::Stale { fencing_token }),
+        })
+    }
+
+    // The current lease and the number of waiters
+    pub fn get(&self, name: &str) -> Option<(Lease, usize)> {
+        self.with_lock(name, |state| {
+            state
+                .lease
+                .clone()
+                .map(|lease| (lease, state.waiters.len()))
+        })
+    }
+
+    // Leases are expired and handed over lazily, whenever the lock is accessed
+    fn with_lock<R>(&self, name: &str, f: impl FnOnce(&mut LockState) -> R) -> R {
+        let mut locks = self.locks.lock().unwrap();
+        let state = locks.entry(name.to_string()).or_default();
+        self.hand_over(state);
+        let result = f(state);
+        self.hand_over(state);
+        if state.lease.is_none() && state.waiters.is_empty() {
+            locks.remove(name);
+        }
+        result
+    }
+
+    // Waiters that gave up in the meantime are skipped
+    fn hand_over(&self, state: &mut LockState) {
+        if state.lease.as_ref().is_some_and(Lease::is_expired) {
+            state.lease = None;
+        }
+        while state.lease.is_none() {
+            let waiter = match state.waiters.pop_front() {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            let lease = self.grant(waiter.ttl);
+            if waiter.sender.send(lease.clone()).is_ok() {
+                state.lease = Some(lease);
+            }
+        }
+    }
+
+    fn grant(&self, ttl: Duration) -> Lease {
+        Lease {
+            token: hex::encode(rand::random::<[u8; 16]>()),
+            fencing_token: self.fencing_tokens.fetch_add(1, Ordering::SeqCst) + 1,
+            expire_at: Utc::now() + ttl,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("The lock is held until {}.", expire_at.to_rfc3339()))]
+    Held { expire_at: DateTime<Utc> },
+    #[snafu(display("The lock is not held."))]
+    NotHeld,
+    #[snafu(display("The lock is held by another owner."))]
+    NotOwner,
+    #[snafu(display("The fencing token {} is stale.", fencing_token))]
+    Stale { fencing_token: u64 },
+}
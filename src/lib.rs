@@ -4,8 +4,20 @@ extern crate clap;
 extern crate serde_derive;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
 
+pub mod auth;
+pub mod compression;
 pub mod configuration;
+pub mod document;
 pub mod kvstore;
+pub mod lease;
 pub mod lucid;
+pub mod metrics;
+pub mod namespace;
+pub mod ratelimit;
 pub mod server;
+pub mod tls;
@@ -4,15 +4,27 @@ extern crate clap;
 extern crate serde_derive;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
 
-extern crate block_modes;
+extern crate block_cipher_trait;
 extern crate hex;
 extern crate serpent;
 
+mod auth;
+mod compression;
 mod configuration;
+mod document;
 mod kvstore;
+mod lease;
 mod lucid;
+mod metrics;
+mod namespace;
+mod ratelimit;
 mod server;
+mod tls;
 
 use self::lucid::Lucid;
 use configuration::{Claims, Configuration, LogOutput};
@@ -97,8 +109,7 @@ async fn main() -> Result<(), Error> {
         }
     };
     let config = if config_path.exists() {
-        serde_yaml::from_reader(File::open(&config_path).context(OpenConfigFile)?)
-            .context(ReadConfigFile)?
+        Configuration::load(&config_path).context(LoadConfigFile)?
     } else {
         Configuration::default()
     };
@@ -159,7 +170,12 @@ async fn start(
         } else {
             let mut config = Configuration::default();
             let secret_key = generate_secret_key();
-            config.authentication.root_token = issue_jwt(&secret_key, None)?;
+            config.authentication.issuer = format!(
+                "http://{}:{}/",
+                config.general.bind_address, config.general.port
+            );
+            config.authentication.root_token =
+                issue_jwt(&secret_key, &config.authentication.issuer, None)?;
             config.authentication.secret_key = secret_key;
             fs::create_dir_all(config_path.parent().unwrap()).context(CreateConfigDir)?;
             serde_yaml::to_writer(
@@ -205,17 +221,22 @@ fn generate_secret_key() -> String {
     )
 }
 
-fn issue_jwt(secret_key: &str, expiration: Option<DateTime<Utc>>) -> Result<String, Error> {
+fn issue_jwt(
+    secret_key: &str,
+    issuer: &str,
+    expiration: Option<DateTime<Utc>>,
+) -> Result<String, Error> {
     jsonwebtoken::encode(
         &Header::default(),
         &Claims {
             sub: String::from("Lucid Root Token"),
-            iss: String::from("http://localhost:7021/"), // TODO: check issuer, maybe set the proper uri
+            iss: issuer.to_string(),
             iat: Utc::now().timestamp(),
             exp: match expiration {
                 Some(exp) => exp.timestamp(),
                 None => (Utc::now() + Duration::weeks(52 * 3)).timestamp(),
             },
+            scope: None,
         },
         secret_key.as_ref(),
     )
@@ -264,8 +285,8 @@ pub enum Error {
     WriteConfigFile { source: serde_yaml::Error },
     #[snafu(display("Unable to open the Lucid configuration file: {}", source))]
     OpenConfigFile { source: std::io::Error },
-    #[snafu(display("Unable to read the Lucid configuration file: {}", source))]
-    ReadConfigFile { source: serde_yaml::Error },
+    #[snafu(display("{}", source))]
+    LoadConfigFile { source: configuration::Error },
     #[snafu(display("Error while encoding the JWT root token: {}", source))]
     EncodeJwt { source: jsonwebtoken::errors::Error },
 }
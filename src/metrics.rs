@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use prometheus::{
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use warp::log::Info;
+
+use crate::kvstore::StoreStats;
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "lucid_http_requests_total",
+        "Number of HTTP requests handled.",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "lucid_http_request_duration_seconds",
+        "Latency of the HTTP requests.",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    pub static ref KEYS: IntGauge =
+        register_int_gauge!("lucid_keys", "Number of keys in the store.").unwrap();
+    pub static ref STORED_BYTES: IntGauge = register_int_gauge!(
+        "lucid_stored_bytes",
+        "Size of the stored values, after encryption."
+    )
+    .unwrap();
+    pub static ref STORED_BYTES_BY_MIME: IntGaugeVec = register_int_gauge_vec!(
+        "lucid_stored_bytes_by_mime_type",
+        "Size of the stored values by MIME type.",
+        &["mime_type"]
+    )
+    .unwrap();
+    pub static ref COMPRESSED_BYTES: IntGauge = register_int_gauge!(
+        "lucid_compressed_bytes",
+        "Size of the compressed values, after compression."
+    )
+    .unwrap();
+    pub static ref COMPRESSED_ORIGINAL_BYTES: IntGauge = register_int_gauge!(
+        "lucid_compressed_original_bytes",
+        "Size of the compressed values, before compression."
+    )
+    .unwrap();
+    pub static ref SSE_SUBSCRIBERS: IntGauge = register_int_gauge!(
+        "lucid_sse_subscribers",
+        "Number of connected Server-Sent Events clients."
+    )
+    .unwrap();
+    pub static ref SSE_LAGGED_EVENTS: IntCounter = register_int_counter!(
+        "lucid_sse_lagged_events_total",
+        "Events dropped because a Server-Sent Events client lagged behind."
+    )
+    .unwrap();
+    pub static ref EXPIRED_KEYS: IntCounter =
+        register_int_counter!("lucid_expired_keys_total", "Number of expired keys.").unwrap();
+    pub static ref EVICTED_KEYS: IntCounter =
+        register_int_counter!("lucid_evicted_keys_total", "Number of evicted keys.").unwrap();
+    pub static ref ENCRYPTION_DURATION: HistogramVec = register_histogram_vec!(
+        "lucid_encryption_duration_seconds",
+        "Time spent encrypting and decrypting values.",
+        &["operation"],
+        prometheus::exponential_buckets(0.000_01, 4.0, 8).unwrap()
+    )
+    .unwrap();
+}
+
+// Keys are left out of the route label to keep the number of series bounded
+pub fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        [""] => "/",
+        ["api", "kv"] => "/api/kv",
+        ["api", "kv", ..] => "/api/kv/{key}",
+        ["api", "ns", _, "kv"] => "/api/ns/{namespace}/kv",
+        ["api", "ns", _, "kv", ..] => "/api/ns/{namespace}/kv/{key}",
+        ["api", "trash"] => "/api/trash",
+        ["api", "trash", ..] => "/api/trash/{key}",
+        ["api", "ns", _, "trash"] => "/api/ns/{namespace}/trash",
+        ["api", "ns", _, "trash", ..] => "/api/ns/{namespace}/trash/{key}",
+        ["api", "locks", ..] => "/api/locks/{name}",
+        ["api", "ns", _, "locks", ..] => "/api/ns/{namespace}/locks/{name}",
+        ["api", "admin", "namespaces"] => "/api/admin/namespaces",
+        ["api", "admin", "namespaces", _] => "/api/admin/namespaces/{namespace}",
+        ["api", "admin", "config", "reload"] => "/api/admin/config/reload",
+        ["api", "admin", "info"] => "/api/admin/info",
+        ["notifications"] => "/notifications",
+        ["health"] => "/health",
+        ["health", "live"] => "/health/live",
+        ["health", "ready"] => "/health/ready",
+        ["metrics"] => "/metrics",
+        ["robots.txt"] => "/robots.txt",
+        _ => "other",
+    }
+}
+
+// MIME types are chosen by the clients, so only well known ones get their own series
+pub fn mime_label(mime_type: &str) -> &'static str {
+    const KNOWN: &[&str] = &[
+        "application/gzip",
+        "application/javascript",
+        "application/json",
+        "application/octet-stream",
+        "application/pdf",
+        "application/xml",
+        "application/zip",
+        "audio/mpeg",
+        "image/gif",
+        "image/jpeg",
+        "image/png",
+        "image/svg+xml",
+        "image/webp",
+        "text/css",
+        "text/csv",
+        "text/html",
+        "text/plain",
+        "text/xml",
+        "video/mp4",
+    ];
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    KNOWN
+        .iter()
+        .find(|known| known.eq_ignore_ascii_case(essence))
+        .copied()
+        .unwrap_or("other")
+}
+
+pub fn observe_request(info: Info) {
+    let route = route_label(info.path());
+    let status = info.status().as_u16().to_string();
+    let labels = [route, info.method().as_str(), status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(info.elapsed().as_secs_f64());
+}
+
+pub fn render(stats: &StoreStats, sse_subscribers: usize) -> String {
+    KEYS.set(stats.keys as i64);
+    STORED_BYTES.set(stats.stored_bytes() as i64);
+    STORED_BYTES_BY_MIME.reset();
+    let mut bytes_by_label = HashMap::new();
+    for (mime_type, bytes) in &stats.bytes_by_mime {
+        *bytes_by_label.entry(mime_label(mime_type)).or_insert(0) += bytes;
+    }
+    for (label, bytes) in bytes_by_label {
+        STORED_BYTES_BY_MIME
+            .with_label_values(&[label])
+            .set(bytes as i64);
+    }
+    COMPRESSED_BYTES.set(stats.compression.stored_bytes as i64);
+    COMPRESSED_ORIGINAL_BYTES.set(stats.compression.original_bytes as i64);
+    SSE_SUBSCRIBERS.set(sse_subscribers as i64);
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("Unable to encode the metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
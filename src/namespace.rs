@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use snafu::Snafu;
+
+use crate::configuration::{Encryption, NamespaceSettings, Store};
+use crate::kvstore::{KvStore, StoreStats};
+use crate::lease::Leases;
+
+pub struct Namespace {
+    // None for the default namespace behind /api/kv/
+    pub name: Option<String>,
+    pub settings: NamespaceSettings,
+    pub store: Arc<KvStore>,
+    pub leases: Leases,
+}
+
+impl Namespace {
+    pub fn event_name(&self, key: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{}:{}", name, key),
+            None => key.to_string(),
+        }
+    }
+}
+
+pub struct Namespaces {
+    default: Arc<Namespace>,
+    named: RwLock<HashMap<String, Arc<Namespace>>>,
+    encryption: Encryption,
+    // Compression and history settings of the created stores
+    store: Store,
+}
+
+impl Namespaces {
+    pub fn new(store: Arc<KvStore>, encryption: &Encryption, settings: &Store) -> Namespaces {
+        Namespaces {
+            default: Arc::new(Namespace {
+                name: None,
+                settings: NamespaceSettings {
+                    encryption: encryption.enabled,
+                    ..Default::default()
+                },
+                store,
+                leases: Leases::new(),
+            }),
+            named: RwLock::new(HashMap::new()),
+            encryption: encryption.clone(),
+            store: settings.clone(),
+        }
+    }
+
+    pub fn default(&self) -> Arc<Namespace> {
+        self.default.clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Namespace>> {
+        self.named.read().unwrap().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Namespace>> {
+        let mut namespaces: Vec<Arc<Namespace>> =
+            self.named.read().unwrap().values().cloned().collect();
+        namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+        namespaces
+    }
+
+    pub fn create(&self, name: &str, settings: NamespaceSettings) -> Result<Arc<Namespace>, Error> {
+        if name.is_empty()
+            || name.len() > 64
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+            });
+        }
+        let cipher = if settings.encryption {
+            if hex_len(&self.encryption.private_key) != Some(24)
+                || hex_len(&self.encryption.iv) != Some(16)
+            {
+                return Err(Error::InvalidEncryptionKey);
+            }
+            Some([
+                self.encryption.private_key.as_str(),
+                self.encryption.iv.as_str(),
+            ])
+        } else {
+            None
+        };
+
+        let mut named = self.named.write().unwrap();
+        if named.contains_key(name) {
+            return Err(Error::AlreadyExists {
+                name: name.to_string(),
+            });
+        }
+        let namespace = Arc::new(Namespace {
+            name: Some(name.to_string()),
+            settings,
+            store: Arc::new(
+                KvStore::new(cipher)
+                    .with_compression(self.store.compression.clone())
+                    .with_history(self.store.history.clone()),
+            ),
+            leases: Leases::new(),
+        });
+        named.insert(name.to_string(), namespace.clone());
+        Ok(namespace)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<Arc<Namespace>, Error> {
+        self.named
+            .write()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| Error::NotFound {
+                name: name.to_string(),
+            })
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let mut stats = self.default.store.stats();
+        for namespace in self.named.read().unwrap().values() {
+            let namespace_stats = namespace.store.stats();
+            stats.keys += namespace_stats.keys;
+            for (mime_type, bytes) in namespace_stats.bytes_by_mime {
+                *stats.bytes_by_mime.entry(mime_type).or_insert(0) += bytes;
+            }
+            stats.compression.add(&namespace_stats.compression);
+        }
+        stats
+    }
+
+    // Quotas apply to what a subject stores across every namespace
+    pub fn owner_bytes(&self, owner: &str) -> u64 {
+        self.default.store.owner_bytes(owner)
+            + self
+                .named
+                .read()
+                .unwrap()
+                .values()
+                .map(|namespace| namespace.store.owner_bytes(owner))
+                .sum::<u64>()
+    }
+}
+
+fn hex_len(value: &str) -> Option<usize> {
+    hex::decode(value).ok().map(|bytes| bytes.len())
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Invalid namespace name \"{}\", use up to 64 letters, digits, - or _.",
+        name
+    ))]
+    InvalidName { name: String },
+    #[snafu(display("The namespace \"{}\" already exists.", name))]
+    AlreadyExists { name: String },
+    #[snafu(display("The namespace \"{}\" does not exist.", name))]
+    NotFound { name: String },
+    #[snafu(display("The encryption key and IV must be set to encrypt a namespace."))]
+    InvalidEncryptionKey,
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::configuration::{Limit, RateLimit, Scope};
+
+// Past this many clients, full buckets are dropped since they would be recreated full anyway
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.capacity());
+        self.updated_at = now;
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(Scope, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    // On rejection, returns how long the client has to wait for the next token
+    pub fn check(&self, config: &RateLimit, scope: Scope, client: &str) -> Result<(), Duration> {
+        let limit = config.limit(scope);
+        if !config.enabled || limit.rate <= 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|(scope, _), bucket| {
+                let limit = config.limit(*scope);
+                bucket.refill(limit, now);
+                bucket.tokens < limit.capacity()
+            });
+        }
+        let bucket = buckets
+            .entry((scope, client.to_string()))
+            .or_insert_with(|| Bucket {
+                tokens: limit.capacity(),
+                updated_at: now,
+            });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate))
+        }
+    }
+}
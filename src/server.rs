@@ -1,27 +1,68 @@
-use std::{net::SocketAddr, sync::RwLock};
+use std::{
+    collections::BTreeMap, convert::Infallible, net::SocketAddr, ops::Range, sync::RwLock,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{os::unix::fs::PermissionsExt, path::Path};
 
 use bytes::{Buf, Bytes};
-use jsonwebtoken::Validation;
-use snafu::Snafu;
-use std::sync::Arc;
-use tokio::{
-    stream::{Stream, StreamExt},
-    sync::broadcast,
+use hyper::{body::HttpBody, Body};
+use chrono::{DateTime, Utc};
+use futures::{
+    future::{self, BoxFuture, Either},
+    stream, Future, FutureExt, StreamExt,
 };
+use snafu::{ResultExt, Snafu};
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{stream::Stream, sync::broadcast, time};
 use warp::{
     self, filters, fs,
-    http::{Response, StatusCode},
-    path, reject, Rejection, Reply,
+    http::{HeaderValue, Response, StatusCode},
+    path,
+    path::{FullPath, Tail},
+    reject,
+    reply::{Json, WithStatus},
+    Rejection, Reply,
 };
+use serde_json::json;
 use warp::{sse::ServerSentEvent, Filter};
 
-use crate::configuration::{Claims, Configuration};
-use crate::kvstore::KvStore;
+use crate::auth::{self, Authenticator, Identity, Keyring};
+use crate::compression::ContentEncoding;
+#[cfg(unix)]
+use crate::configuration::General;
+use crate::configuration::{
+    self, Configuration, EvictionPolicy, NamespaceSettings, ReloadReport, Scope,
+};
+use crate::document;
+use crate::kvstore::{self, DataType, KvElement, KvStore, Limits, CHUNK_SIZE};
+use crate::lease::{self, Acquire, Lease, Leases};
+use crate::metrics;
+use crate::namespace::{self, Namespace, Namespaces};
+use crate::ratelimit::RateLimiter;
+use crate::tls::{self, ConnectionInfo, TlsConfig};
+
+// Smaller responses are not worth compressing
+const MIN_COMPRESSED_RESPONSE: usize = 256;
+
+// Only the start of larger values is notified, clients can fetch the rest
+const MAX_NOTIFIED_VALUE: usize = 4096;
 
 #[derive(Debug, Clone)]
-pub struct SseMessage {
-    key: String,
-    value: String,
+pub enum SseMessage {
+    Update { key: String, value: String },
+    // A None value means the field was deleted
+    FieldUpdate {
+        key: String,
+        field: String,
+        value: Option<String>,
+    },
+    Shutdown,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,6 +70,37 @@ struct JsonMessage {
     message: String,
 }
 
+#[derive(Deserialize)]
+struct PrefixQuery {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+struct ExpirationQuery {
+    ttl: Option<String>,
+    expire_at: Option<String>,
+}
+
+struct NewValue {
+    // Stored as they are, see read_body
+    body: Vec<Vec<u8>>,
+    mime: Option<String>,
+    expire_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct KeyList {
+    keys: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ReloadMessage {
+    message: String,
+    #[serde(flatten)]
+    report: ReloadReport,
+}
+
 pub struct Server {
     configuration: Arc<RwLock<Configuration>>,
 }
@@ -39,7 +111,7 @@ impl Server {
     }
 
     pub async fn run(&self) {
-        let configuration = self.configuration.read().unwrap();
+        let configuration = self.configuration.read().unwrap().clone();
 
         let mut encryption_key = None;
         if configuration.encryption.enabled {
@@ -54,20 +126,27 @@ impl Server {
                 ]);
             }
         }
-        let store = Arc::new(KvStore::new(encryption_key));
+        if configuration.authentication.enabled {
+            if let Err(e) = Keyring::load(&configuration.authentication) {
+                panic!("{}", e);
+            }
+        }
+        let store = Arc::new(
+            KvStore::new(encryption_key)
+                .with_compression(configuration.store.compression.clone())
+                .with_history(configuration.store.history.clone()),
+        );
         let event_tx = Arc::new(broadcast::channel(512).0); // TODO: Specify in configuration (maybe?)
 
-        let instance = warp::serve(routes_filter(store, event_tx, self.configuration.clone()));
+        let routes = routes_filter(store, event_tx.clone(), self.configuration.clone());
+        let shutdown_tx = broadcast::channel(1).0;
+        let mut listeners: Vec<BoxFuture<'static, ()>> = Vec::new();
+        info!("Running Lucid server | PID: {}", std::process::id());
         if configuration.general.use_ssl {
             let bind_endpoint = SocketAddr::from((
                 configuration.general.bind_address,
                 configuration.general.port_ssl,
             ));
-            info!(
-                "Running Lucid server on {} | PID: {}",
-                bind_endpoint,
-                std::process::id()
-            );
             info!("Lucid API Endpoint: https://{}/api/", bind_endpoint);
             info!(
                 "SSL Certificate: {}",
@@ -77,101 +156,527 @@ impl Server {
                 "SSL Private Key: {}",
                 &configuration.general.ssl_certificate_key
             );
-            info!("Use Ctrl+C to stop the server.");
-            instance
-                .tls()
-                .cert_path(&configuration.general.ssl_certificate)
-                .key_path(&configuration.general.ssl_certificate_key)
-                .bind((
-                    configuration.general.bind_address,
-                    configuration.general.port_ssl,
-                ))
-                .await;
-        } else {
+            if !configuration.general.ssl_client_ca.is_empty() {
+                info!(
+                    "SSL Client CA: {}",
+                    &configuration.general.ssl_client_ca
+                );
+            }
+            let tls_config = match TlsConfig::new(&configuration.general) {
+                Ok(tls_config) => tls_config,
+                Err(e) => panic!("{}", e),
+            };
+            if configuration.general.ssl_reload_interval > 0 {
+                tokio::spawn(tls_config.clone().watch_files(Duration::from_secs(
+                    configuration.general.ssl_reload_interval,
+                )));
+            }
+            #[cfg(unix)]
+            tokio::spawn(tls_config.clone().watch_hangup());
+            listeners.push(
+                tls::serve(routes.clone(), bind_endpoint, tls_config, shutdown_tx.clone())
+                    .map(|result| {
+                        if let Err(e) = result {
+                            error!("Unable to run the HTTPS server: {}", e);
+                        }
+                    })
+                    .boxed(),
+            );
+        }
+        if !configuration.general.use_ssl || configuration.general.http_alongside_ssl {
             let bind_endpoint = SocketAddr::from((
                 configuration.general.bind_address,
                 configuration.general.port,
             ));
+            if configuration.general.use_ssl && configuration.general.redirect_http_to_ssl {
+                info!(
+                    "Redirecting http://{}/ to port {}",
+                    bind_endpoint, configuration.general.port_ssl
+                );
+                listeners.push(
+                    warp::serve(redirect_filter(configuration.general.port_ssl))
+                        .bind_with_graceful_shutdown(bind_endpoint, shutdown_signal(&shutdown_tx))
+                        .1
+                        .boxed(),
+                );
+            } else {
+                info!("Lucid API Endpoint: http://{}/api/", bind_endpoint);
+                listeners.push(
+                    warp::serve(routes.clone())
+                        .bind_with_graceful_shutdown(bind_endpoint, shutdown_signal(&shutdown_tx))
+                        .1
+                        .boxed(),
+                );
+            }
+        }
+        if !configuration.general.unix_socket.is_empty() {
+            #[cfg(unix)]
+            match bind_unix_socket(&configuration.general) {
+                Ok(listener) => {
+                    info!(
+                        "Lucid API Endpoint: unix:{}",
+                        &configuration.general.unix_socket
+                    );
+                    let incoming = stream::unfold(listener, |mut listener| async {
+                        let connection = listener.accept().await.map(|(stream, _)| stream);
+                        Some((connection, listener))
+                    });
+                    listeners.push(
+                        warp::serve(routes.clone())
+                            .serve_incoming_with_graceful_shutdown(
+                                incoming,
+                                shutdown_signal(&shutdown_tx),
+                            )
+                            .boxed(),
+                    );
+                }
+                Err(e) => panic!(
+                    "Unable to bind the Unix socket {}: {}",
+                    &configuration.general.unix_socket, e
+                ),
+            }
+            #[cfg(not(unix))]
+            warn!("Unix domain sockets are not supported on this platform.");
+        }
+        #[cfg(unix)]
+        tokio::spawn(watch_hangup(self.configuration.clone()));
+        info!("Use Ctrl+C to stop the server.");
+        let listeners = future::join_all(listeners);
+        if let Either::Right((_, listeners)) =
+            future::select(listeners, termination_signal().boxed()).await
+        {
             info!(
-                "Running Lucid server on {} | PID: {}",
-                bind_endpoint,
-                std::process::id()
+                "Shutting down, draining connections for up to {} seconds...",
+                configuration.general.drain_timeout
             );
-            info!("Lucid API Endpoint: http://{}/api/", bind_endpoint);
-            info!("Use Ctrl+C to stop the server.");
-            instance
-                .bind((
-                    configuration.general.bind_address,
-                    configuration.general.port,
-                ))
-                .await;
+            event_tx.send(SseMessage::Shutdown).ok();
+            shutdown_tx.send(()).ok();
+            match time::timeout(
+                Duration::from_secs(configuration.general.drain_timeout),
+                listeners,
+            )
+            .await
+            {
+                Ok(_) => info!("All connections drained."),
+                Err(_) => warn!("Drain timeout elapsed, closing the remaining connections."),
+            }
         }
+        #[cfg(unix)]
+        {
+            if !configuration.general.unix_socket.is_empty() {
+                std::fs::remove_file(&configuration.general.unix_socket).ok();
+            }
+        }
+    }
+}
+
+fn shutdown_signal(shutdown_tx: &broadcast::Sender<()>) -> impl Future<Output = ()> {
+    let mut shutdown_rx = shutdown_tx.subscribe();
+    async move {
+        shutdown_rx.recv().await.ok();
     }
 }
 
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                future::select(tokio::signal::ctrl_c().boxed(), terminate.recv().boxed()).await;
+            }
+            Err(e) => {
+                error!("Unable to listen for SIGTERM: {}", e);
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.ok();
+    }
+}
+
+#[cfg(unix)]
+async fn watch_hangup(config: Arc<RwLock<Configuration>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Unable to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match reload_configuration(&config) {
+            Ok(report) => log_reload(&report),
+            Err(e) => error!("Unable to reload the configuration: {}", e),
+        }
+    }
+}
+
+pub fn reload_configuration(config: &RwLock<Configuration>) -> Result<ReloadReport, Error> {
+    let source = config
+        .read()
+        .unwrap()
+        .source
+        .clone()
+        .ok_or(Error::MissingConfigFile)?;
+    let new_config = Configuration::load(&source).context(LoadConfiguration)?;
+    if new_config.authentication.enabled {
+        Keyring::load(&new_config.authentication).context(InvalidAuthentication)?;
+    }
+    let mut config = config.write().unwrap();
+    let report = config.reload(new_config);
+    log::set_max_level(config.logging.level);
+    Ok(report)
+}
+
+fn log_reload(report: &ReloadReport) {
+    info!(
+        "Configuration reloaded, {} setting(s) applied.",
+        report.reloaded.len()
+    );
+    if !report.restart_required.is_empty() {
+        warn!(
+            "These settings only take effect after a restart: {}",
+            report.restart_required.join(", ")
+        );
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix_socket(general: &General) -> std::io::Result<UnixListener> {
+    let path = Path::new(&general.unix_socket);
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let mode = u32::from_str_radix(&general.unix_socket_mode, 8)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+pub fn redirect_filter(
+    port_ssl: u16,
+) -> impl Filter<Extract = (impl Reply,)> + Clone + Send + Sync + 'static {
+    warp::header::optional::<String>("host")
+        .and(path::full())
+        .and(
+            warp::query::raw()
+                .or(warp::any().map(String::new))
+                .unify(),
+        )
+        .map(move |host: Option<String>, path: FullPath, query: String| {
+            let host = host.unwrap_or_default();
+            let hostname = if host.starts_with('[') {
+                host.split(']').next().map(|ip| format!("{}]", ip))
+            } else {
+                host.split(':').next().map(String::from)
+            }
+            .unwrap_or_default();
+            let mut location = format!("https://{}:{}{}", hostname, port_ssl, path.as_str());
+            if !query.is_empty() {
+                location.push('?');
+                location.push_str(&query);
+            }
+            Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header("Location", location)
+                .body("")
+        })
+}
+
 pub fn routes_filter(
     store: Arc<KvStore>,
     event_tx: Arc<broadcast::Sender<SseMessage>>,
     config: Arc<RwLock<Configuration>>,
 ) -> impl Filter<Extract = (impl Reply,)> + Clone + Send + Sync + 'static {
-    let configuration = config.read().unwrap();
+    let started_at = Utc::now();
+    let started_at = warp::any().map(move || started_at);
 
-    let store = warp::any().map(move || store.clone());
+    let configuration = config.read().unwrap().clone();
+    let namespaces = Arc::new(Namespaces::new(
+        store,
+        &configuration.encryption,
+        &configuration.store,
+    ));
+    for (name, settings) in &configuration.namespaces {
+        if let Err(e) = namespaces.create(name, settings.clone()) {
+            error!("Unable to create a namespace: {}", e);
+        }
+    }
+    let namespaces = warp::any().map(move || namespaces.clone());
     let event_tx = warp::any().map(move || event_tx.clone());
 
     let config = config.clone();
     let config = warp::any().map(move || config.clone());
 
-    let auth = warp::header::optional::<String>("authorization")
+    let authenticator = Arc::new(Authenticator::new());
+    let authenticator = warp::any().map(move || authenticator.clone());
+
+    let authenticate = warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::ext::optional::<ConnectionInfo>())
         .and(config.clone())
-        .and_then(verify_auth)
-        .untuple_one();
+        .and(authenticator)
+        .and_then(verify_auth);
+    let rate_limiter = Arc::new(RateLimiter::new());
+    let rate_limiter = warp::any().map(move || rate_limiter.clone());
+
+    let remote_addr = warp::ext::optional::<ConnectionInfo>()
+        .and(warp::addr::remote())
+        .map(|connection: Option<ConnectionInfo>, remote_addr: Option<SocketAddr>| {
+            connection
+                .map(|connection| connection.remote_addr)
+                .or(remote_addr)
+        });
+    let rate_limit_config = config.clone();
+    let identity = move |scope: Scope| {
+        authenticate
+            .clone()
+            .and(remote_addr)
+            .and(rate_limit_config.clone())
+            .and(rate_limiter.clone())
+            .and_then(move |identity, remote_addr, config, rate_limiter| {
+                check_rate_limit(identity, scope, remote_addr, config, rate_limiter)
+            })
+            .and_then(move |identity: Identity| authorize(identity, scope))
+    };
+    let auth = {
+        let identity = identity.clone();
+        move |scope: Scope| identity(scope).map(|_: Identity| ()).untuple_one()
+    };
 
     let webui_enabled = config.clone().and_then(check_webui).untuple_one();
 
     let sse_enabled = config.clone().and_then(check_sse).untuple_one();
 
+    let metrics_enabled = config.clone().and_then(check_metrics).untuple_one();
+
+    let metrics_public = config.clone().and_then(check_metrics_public).untuple_one();
+
     let mime = warp::header::optional::<String>("content-type");
 
-    let api_kv_key_path = path!("api" / "kv" / String)
-        .and(path::end());
-
-    let api_kv_key = auth.clone().and(
-        warp::get()
-            .and(store.clone())
-            .and(api_kv_key_path)
-            .and_then(get_key)
-            .or(warp::put()
-                .and(store.clone())
-                .and(event_tx.clone())
-                .and(config.clone())
-                .and(api_kv_key_path)
-                .and(filters::body::content_length_limit(
-                    configuration.http.request_size_limit,
-                ))
-                .and(filters::body::content_length_limit(
-                    configuration.store.max_limit,
-                ))
-                .and(warp::body::bytes())
-                .and(mime.clone())
-                .and_then(put_key))
-            .or(warp::delete()
-                .and(store.clone())
-                .and(api_kv_key_path)
-                .and_then(delete_key))
-            .or(warp::head()
-                .and(store.clone())
-                .and(api_kv_key_path)
-                .and_then(find_key))
-            .or(warp::patch()
-                .and(store.clone())
-                .and(api_kv_key_path)
-                .and(filters::body::content_length_limit(
-                    configuration.http.request_size_limit,
-                ))
-                .and(filters::body::json())
-                .and_then(patch_key)),
-    );
+    let request_size_limit = warp::header::optional::<u64>("content-length")
+        .and(config.clone())
+        .and_then(check_request_size)
+        .untuple_one();
+
+    let new_value = request_size_limit
+        .clone()
+        .and(warp::header::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and_then(read_body)
+        .and(mime)
+        .and(warp::header::optional::<String>("x-lucid-ttl"))
+        .and(warp::query::<ExpirationQuery>())
+        .and_then(parse_new_value);
+
+    let api_kv_namespace = path!("api" / "kv" / ..)
+        .and(namespaces.clone())
+        .map(|namespaces: Arc<Namespaces>| namespaces.default())
+        .or(path!("api" / "ns" / String / "kv" / ..)
+            .and(namespaces.clone())
+            .and_then(find_namespace))
+        .unify();
+
+    // The rest of the path is the key, so keys can be hierarchical like users/42/profile
+    let api_kv_key_path = api_kv_namespace
+        .clone()
+        .and(path::tail())
+        .and_then(key_from_tail)
+        .untuple_one();
+
+    // Writes sent with a fencing token are refused unless the lock named after the
+    // key is still held under that token. A key locked with the lock operation
+    // cannot be updated by the holder of the lock either, it has to be unlocked.
+    let api_kv_write_key = api_kv_key_path
+        .clone()
+        .and(identity(Scope::Write))
+        .and(warp::header::optional::<u64>("x-lucid-fencing-token"))
+        .and_then(check_fencing_token)
+        .untuple_one();
+
+    let api_kv_store_key = api_kv_key_path
+        .clone()
+        .map(|namespace: Arc<Namespace>, key: String| (namespace.store.clone(), key))
+        .untuple_one();
+
+    let api_kv_key = warp::get()
+        .and(api_kv_store_key.clone())
+        .and(auth(Scope::Read))
+        .and(warp::query::<GetQuery>())
+        .and(warp::query::<Vec<(String, String)>>().map(|params: Vec<(String, String)>| {
+            params
+                .into_iter()
+                .filter(|(name, _)| name == "intersect")
+                .map(|(_, key)| key)
+                .collect::<Vec<String>>()
+        }))
+        .and(warp::header::optional::<String>("range"))
+        .and_then(get_key)
+        .or(warp::put()
+            .and(event_tx.clone())
+            .and(config.clone())
+            .and(namespaces.clone())
+            .and(api_kv_write_key.clone())
+            .and(new_value)
+            .and_then(put_key))
+        .or(warp::delete()
+            .and(config.clone())
+            .and(
+                api_kv_write_key
+                    .clone()
+                    .map(|namespace: Arc<Namespace>, key: String, _: Identity| {
+                        (namespace.store.clone(), key)
+                    })
+                    .untuple_one(),
+            )
+            .and_then(delete_key))
+        .or(warp::head()
+            .and(api_kv_store_key.clone())
+            .and(auth(Scope::Read))
+            .and_then(find_key))
+        .or(warp::patch()
+            .and(warp::header::<String>("content-type").and_then(check_patch_format))
+            .and(config.clone())
+            .and(namespaces.clone())
+            .and(api_kv_write_key.clone())
+            .and(request_size_limit.clone())
+            .and(warp::body::bytes())
+            .and_then(patch_document))
+        .or(warp::patch()
+            .and(event_tx.clone())
+            .and(config.clone())
+            .and(namespaces.clone())
+            .and(api_kv_write_key)
+            .and(request_size_limit.clone())
+            .and(filters::body::json())
+            .and_then(patch_key));
+
+    let api_kv_prefix = api_kv_namespace
+        .and(path::end())
+        .and(warp::query::<PrefixQuery>());
+
+    let api_kv_list = warp::get()
+        .and(api_kv_prefix.clone())
+        .and(auth(Scope::Read))
+        .map(list_keys)
+        .or(warp::delete()
+            .and(config.clone())
+            .and(api_kv_prefix)
+            .and(auth(Scope::Write))
+            .and_then(delete_prefix));
+
+    let api_trash_namespace = path!("api" / "trash" / ..)
+        .and(namespaces.clone())
+        .map(|namespaces: Arc<Namespaces>| namespaces.default())
+        .or(path!("api" / "ns" / String / "trash" / ..)
+            .and(namespaces.clone())
+            .and_then(find_namespace))
+        .unify();
+
+    let api_trash_key = api_trash_namespace
+        .clone()
+        .and(path::tail())
+        .and_then(key_from_tail)
+        .untuple_one();
+
+    let api_trash_prefix = api_trash_namespace
+        .and(path::end())
+        .and(warp::query::<PrefixQuery>());
+
+    let api_trash = warp::get()
+        .and(api_trash_prefix.clone())
+        .and(auth(Scope::Read))
+        .map(list_trash)
+        .or(warp::delete()
+            .and(api_trash_prefix)
+            .and(auth(Scope::Write))
+            .map(empty_trash))
+        .or(warp::delete()
+            .and(api_trash_key.clone())
+            .and(auth(Scope::Write))
+            .and_then(purge_key))
+        .or(warp::patch()
+            .and(config.clone())
+            .and(namespaces.clone())
+            .and(api_trash_key)
+            .and(auth(Scope::Write))
+            .and(request_size_limit.clone())
+            .and(filters::body::json())
+            .and_then(undelete_key));
+
+    let api_locks_namespace = path!("api" / "locks" / ..)
+        .and(namespaces.clone())
+        .map(|namespaces: Arc<Namespaces>| namespaces.default())
+        .or(path!("api" / "ns" / String / "locks" / ..)
+            .and(namespaces.clone())
+            .and_then(find_namespace))
+        .unify();
+
+    let api_lock = api_locks_namespace
+        .and(path::tail())
+        .and_then(key_from_tail)
+        .untuple_one();
+
+    let lock_token = warp::header::optional::<String>("x-lucid-lock-token");
+
+    let api_locks = warp::post()
+        .and(config.clone())
+        .and(api_lock.clone())
+        .and(auth(Scope::Write))
+        .and(warp::query::<LockQuery>())
+        .and_then(acquire_lock)
+        .or(warp::patch()
+            .and(config.clone())
+            .and(api_lock.clone())
+            .and(auth(Scope::Write))
+            .and(lock_token)
+            .and(warp::query::<LockQuery>())
+            .and_then(renew_lock))
+        .or(warp::delete()
+            .and(api_lock.clone())
+            .and(auth(Scope::Write))
+            .and(lock_token)
+            .and_then(release_lock))
+        .or(warp::get()
+            .and(api_lock)
+            .and(auth(Scope::Read))
+            .and_then(find_lock));
+
+    let api_admin = path!("api" / "admin" / "config" / "reload")
+        .and(path::end())
+        .and(warp::post())
+        .and(auth(Scope::Admin))
+        .and(config.clone())
+        .and_then(reload_config)
+        .or(path!("api" / "admin" / "info")
+            .and(path::end())
+            .and(warp::get())
+            .and(auth(Scope::Admin))
+            .and(namespaces.clone())
+            .and(event_tx.clone())
+            .and(config.clone())
+            .and(started_at)
+            .map(server_info))
+        .or(path!("api" / "admin" / "namespaces")
+            .and(warp::get())
+            .and(auth(Scope::Admin))
+            .and(namespaces.clone())
+            .map(list_namespaces))
+        .or(path!("api" / "admin" / "namespaces" / String)
+            .and(warp::put())
+            .and(auth(Scope::Admin))
+            .and(namespaces.clone())
+            .and(request_size_limit)
+            .and(filters::body::json())
+            .and_then(create_namespace))
+        .or(path!("api" / "admin" / "namespaces" / String)
+            .and(warp::delete())
+            .and(auth(Scope::Admin))
+            .and(namespaces.clone())
+            .and_then(delete_namespace));
 
     const WELCOME_PAGE: &'static str = include_str!("../assets/welcome.html");
 
@@ -190,12 +695,33 @@ pub fn routes_filter(
         .allow_any_origin();
 
     let health = path!("health")
-        .map(|| StatusCode::OK);
+        .map(|| StatusCode::OK)
+        .or(path!("health" / "live").map(|| {
+            warp::reply::json(&json!({ "status": "alive" }))
+        }))
+        .or(path!("health" / "ready")
+            .and(namespaces.clone())
+            .and(config.clone())
+            .map(readiness));
+
+    let prometheus_metrics = path!("metrics")
+        .and(warp::get())
+        .and(metrics_enabled)
+        .and(metrics_public.or(auth(Scope::Read)).unify())
+        .and(namespaces)
+        .and(event_tx.clone())
+        .map(
+            |namespaces: Arc<Namespaces>, event_tx: Arc<broadcast::Sender<SseMessage>>| {
+                Response::builder()
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(metrics::render(&namespaces.stats(), event_tx.receiver_count()))
+            },
+        );
 
     let sse = warp::path("notifications")
         .and(warp::get())
         .and(event_tx)
-        .and(auth)
+        .and(auth(Scope::Read))
         .and(sse_enabled)
         .map(|event_tx: Arc<broadcast::Sender<SseMessage>>| {
             let stream = sse_event_stream(event_tx.subscribe());
@@ -203,53 +729,94 @@ pub fn routes_filter(
         });
 
     api_kv_key
+        .or(api_kv_list)
+        .or(api_trash)
+        .or(api_locks)
+        .or(api_admin)
         .or(webui)
         .or(sse)
         .or(robots)
         .or(health)
+        .or(prometheus_metrics)
         .recover(process_error)
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(config)
+        .and_then(compress_response)
         .with(warp::reply::with::header(
             "Server",
             format!("Lucid v{}", crate_version!()),
         ))
         .with(cors)
         .with(warp::log("lucid::server"))
+        .with(warp::log::custom(metrics::observe_request))
 }
 
 async fn put_key(
-    store: Arc<KvStore>,
     event_tx: Arc<broadcast::Sender<SseMessage>>,
     config: Arc<RwLock<Configuration>>,
+    namespaces: Arc<Namespaces>,
+    namespace: Arc<Namespace>,
     key: String,
-    body: Bytes,
-    mime: Option<String>,
+    identity: Identity,
+    value: NewValue,
 ) -> Result<impl Reply, Rejection> {
-    if body.remaining() == 0 {
+    let NewValue {
+        body,
+        mime,
+        expire_at,
+    } = value;
+    let max_limit = max_limit(&config, &namespace);
+    let size = body.iter().map(Vec::len).sum::<usize>() as u64;
+    if size == 0 {
         Err(reject::custom(Error::MissingBody))
-    } else if body.bytes().len() as u64 > config.read().unwrap().store.max_limit {
-        Err(reject::custom(Error::ValueSizeLimit {
-            max_limit: config.read().unwrap().store.max_limit,
-        }))
+    } else if size > max_limit {
+        Err(reject::custom(Error::ValueSizeLimit { max_limit }))
     } else {
-        match store.set(key.clone(), body.to_vec(), mime) {
+        make_room(&namespace, &key, size);
+        // Without an explicit expiration, the namespace TTL takes precedence over the store one
+        let default_ttl = match namespace.settings.default_ttl {
+            0 => config.read().unwrap().store.default_ttl,
+            default_ttl => default_ttl,
+        };
+        let expire_at = expire_at.or_else(|| match default_ttl {
+            0 => None,
+            ttl => Some(Utc::now() + chrono::Duration::seconds(ttl as i64)),
+        });
+        // The body is moved into the store, so the notification is prepared beforehand
+        let notification = if config.read().unwrap().sse.enabled {
+            Some(notified_value(&body))
+        } else {
+            None
+        };
+        let check = |kv_element: &KvElement, replaced: Option<&KvElement>| {
+            check_growth(&config, &namespaces, &namespace, kv_element, replaced)
+        };
+        let limits = Limits {
+            owner: Some(&identity.name),
+            check: &check,
+            ..Default::default()
+        };
+        match namespace
+            .store
+            .set(key.clone(), body, mime, expire_at, &limits)
+            .map_err(value_error)?
+        {
             Some(kv_element) => {
                 if kv_element.locked {
                     Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
                         message: "The specified key cannot be updated, it is currently locked.".to_string(),
                     }), StatusCode::FORBIDDEN))
                 } else {
-                    if config.read().unwrap().sse.enabled {
+                    if let Some(notification) = notification {
                         // TODO: Send an SSE fallaback message for binary data
-                        match String::from_utf8((&body).bytes().to_vec()) {
+                        match notification {
                             Ok(byte_to_string) => {
                                 event_tx
-                                    .send(SseMessage {
-                                        key: key.clone(),
+                                    .send(SseMessage::Update {
+                                        key: namespace.event_name(&key),
                                         value: byte_to_string,
                                     })
-                                    .map_err(|error| {
-                                        error!("Unable to broadcast this key: {:?}", &error.0.key)
-                                    })
+                                    .map_err(|_| error!("Unable to broadcast this key: {:?}", &key))
                                     .ok();
                             }
                             Err(error) => warn!("Unable to broadcast binary data, {}", error),
@@ -267,21 +834,457 @@ async fn put_key(
     }
 }
 
-async fn get_key(store: Arc<KvStore>, key: String) -> Result<impl Reply, Rejection> {
-    match store.get(key) {
-        Some(value) => Ok(Response::builder()
+// The first chunk holds at least MAX_NOTIFIED_VALUE bytes unless it is the only
+// one, a character cut at the end of the preview is left out
+fn notified_value(body: &[Vec<u8>]) -> Result<String, std::str::Utf8Error> {
+    let first = body.first().map_or(&[][..], Vec::as_slice);
+    let preview = &first[..first.len().min(MAX_NOTIFIED_VALUE)];
+    match std::str::from_utf8(preview) {
+        Err(e) if preview.len() < first.len() && e.error_len().is_none() => {
+            Ok(String::from_utf8_lossy(&preview[..e.valid_up_to()]).into_owned())
+        }
+        preview => preview.map(String::from),
+    }
+}
+
+// The body is split as it arrives into the chunks the value is stored by, so it
+// is never held in a single buffer
+async fn read_body(
+    content_length: u64,
+    mut body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> Result<Vec<Vec<u8>>, Rejection> {
+    let mut remaining = content_length as usize;
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::with_capacity(remaining.min(CHUNK_SIZE));
+    while let Some(buf) = body.next().await {
+        let mut buf = buf.map_err(|_| reject::custom(Error::ReadBody))?;
+        while buf.has_remaining() {
+            let len = buf.bytes().len().min(CHUNK_SIZE - chunk.len());
+            chunk.extend_from_slice(&buf.bytes()[..len]);
+            buf.advance(len);
+            if chunk.len() == CHUNK_SIZE {
+                remaining = remaining.saturating_sub(CHUNK_SIZE);
+                let next = Vec::with_capacity(remaining.min(CHUNK_SIZE));
+                chunks.push(std::mem::replace(&mut chunk, next));
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+async fn parse_new_value(
+    body: Vec<Vec<u8>>,
+    mime: Option<String>,
+    ttl_header: Option<String>,
+    query: ExpirationQuery,
+) -> Result<NewValue, Rejection> {
+    let expire_at = match (query.ttl.or(ttl_header), query.expire_at) {
+        (None, None) => None,
+        (Some(ttl), None) => match ttl.trim().parse::<i64>() {
+            Ok(ttl) if ttl > 0 => Some(Utc::now() + chrono::Duration::seconds(ttl)),
+            _ => {
+                return Err(reject::custom(Error::InvalidExpiration {
+                    reason: "the TTL must be a positive number of seconds".to_string(),
+                }))
+            }
+        },
+        (None, Some(expire_at)) => match DateTime::parse_from_rfc3339(&expire_at) {
+            Ok(expire_at) if expire_at.with_timezone(&Utc) > Utc::now() => {
+                Some(expire_at.with_timezone(&Utc))
+            }
+            Ok(_) => {
+                return Err(reject::custom(Error::InvalidExpiration {
+                    reason: "the expiration date is in the past".to_string(),
+                }))
+            }
+            Err(_) => {
+                return Err(reject::custom(Error::InvalidExpiration {
+                    reason: "the expiration date must be in RFC 3339 format".to_string(),
+                }))
+            }
+        },
+        (Some(_), Some(_)) => {
+            return Err(reject::custom(Error::InvalidExpiration {
+                reason: "use either a TTL or an expiration date".to_string(),
+            }))
+        }
+    };
+    Ok(NewValue {
+        body,
+        mime,
+        expire_at,
+    })
+}
+
+// Eviction runs before the write, whose limits are checked as it is stored
+fn make_room(namespace: &Namespace, key: &str, size: u64) {
+    if namespace.settings.max_bytes > 0 {
+        let replaced = namespace.store.entry_size(key).map_or(0, |(size, _)| size);
+        evict(namespace, key, size.saturating_sub(replaced));
+    }
+}
+
+fn evict(namespace: &Namespace, key: &str, needed: u64) {
+    let max_bytes = namespace.settings.max_bytes;
+    if max_bytes > 0 && namespace.settings.eviction == EvictionPolicy::Oldest {
+        let evicted = namespace.store.evict(needed, max_bytes);
+        if evicted > 0 {
+            debug!("Evicted {} keys to store {:?}", evicted, key);
+        }
+    }
+}
+
+// The limits of every write. The owner of the value is charged for what it
+// adds, a value replaced under another owner is not deducted from its usage.
+fn check_growth(
+    config: &RwLock<Configuration>,
+    namespaces: &Namespaces,
+    namespace: &Namespace,
+    kv_element: &KvElement,
+    replaced: Option<&KvElement>,
+) -> Result<(), kvstore::Error> {
+    let max_limit = max_limit(config, namespace);
+    if kv_element.size as u64 > max_limit {
+        return Err(kvstore::Error::ValueTooLarge { max_limit });
+    }
+    let size = kv_element.data.len() as u64;
+    let added = |replaced: Option<&KvElement>| {
+        size.saturating_sub(replaced.map_or(0, |replaced| replaced.data.len() as u64))
+    };
+    if let Some(owner) = &kv_element.owner {
+        let max_bytes = config.read().unwrap().quotas.max_bytes_for(owner);
+        let added = added(replaced.filter(|replaced| replaced.owner.as_ref() == Some(owner)));
+        if max_bytes > 0 && added > 0 && namespaces.owner_bytes(owner) + added > max_bytes {
+            return Err(kvstore::Error::QuotaExceeded { max_bytes });
+        }
+    }
+    let max_bytes = namespace.settings.max_bytes;
+    let added = added(replaced);
+    if max_bytes > 0 && added > 0 && namespace.store.stored_bytes() + added > max_bytes {
+        return Err(kvstore::Error::NamespaceFull { max_bytes });
+    }
+    Ok(())
+}
+
+fn max_limit(config: &RwLock<Configuration>, namespace: &Namespace) -> u64 {
+    match namespace.settings.max_limit {
+        0 => config.read().unwrap().store.max_limit,
+        max_limit => max_limit,
+    }
+}
+
+async fn find_namespace(
+    name: String,
+    namespaces: Arc<Namespaces>,
+) -> Result<Arc<Namespace>, Rejection> {
+    match namespaces.get(&name) {
+        Some(namespace) => Ok(namespace),
+        None => Err(reject::custom(Error::InvalidNamespace {
+            source: namespace::Error::NotFound { name },
+        })),
+    }
+}
+
+async fn key_from_tail(
+    namespace: Arc<Namespace>,
+    tail: Tail,
+) -> Result<(Arc<Namespace>, String), Rejection> {
+    match tail.as_str() {
+        "" => Err(reject::not_found()),
+        key => Ok((namespace, key.to_string())),
+    }
+}
+
+fn list_keys(namespace: Arc<Namespace>, query: PrefixQuery) -> impl Reply {
+    warp::reply::json(&KeyList {
+        keys: namespace.store.keys_with_prefix(&query.prefix),
+    })
+}
+
+async fn delete_prefix(
+    config: Arc<RwLock<Configuration>>,
+    namespace: Arc<Namespace>,
+    query: PrefixQuery,
+) -> Result<impl Reply, Rejection> {
+    // Refuse to empty a whole namespace through a missing parameter
+    if query.prefix.is_empty() {
+        return Err(reject::custom(Error::MissingParameter {
+            parameter: "prefix".to_string(),
+        }));
+    }
+    let keys = match trash_retention(&config) {
+        Some(retention) => namespace.store.trash_prefix(&query.prefix, retention),
+        None => namespace.store.drop_prefix(&query.prefix),
+    };
+    Ok(warp::reply::json(&KeyList { keys }))
+}
+
+fn list_namespaces(namespaces: Arc<Namespaces>) -> impl Reply {
+    let namespaces: Vec<serde_json::Value> = namespaces
+        .list()
+        .iter()
+        .map(|namespace| {
+            let stats = namespace.store.stats();
+            json!({
+                "name": namespace.name,
+                "settings": namespace.settings,
+                "keys": stats.keys,
+                "stored_bytes": stats.stored_bytes(),
+            })
+        })
+        .collect();
+    warp::reply::json(&namespaces)
+}
+
+async fn create_namespace(
+    name: String,
+    namespaces: Arc<Namespaces>,
+    settings: NamespaceSettings,
+) -> Result<impl Reply, Rejection> {
+    namespaces
+        .create(&name, settings)
+        .context(InvalidNamespace)
+        .map_err(reject::custom)?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonMessage {
+            message: "The namespace was successfully created.".to_string(),
+        }),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn delete_namespace(
+    name: String,
+    namespaces: Arc<Namespaces>,
+) -> Result<impl Reply, Rejection> {
+    namespaces
+        .delete(&name)
+        .context(InvalidNamespace)
+        .map_err(reject::custom)?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonMessage {
+            message: "The namespace and its keys were successfully deleted.".to_string(),
+        }),
+        StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn get_key(
+    store: Arc<KvStore>,
+    key: String,
+    query: GetQuery,
+    intersect: Vec<String>,
+    range: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    // Keys ending with /history are still read as values when they exist
+    if let Some(history_key) = key.strip_suffix("/history") {
+        if store.data_type(&key).is_none() {
+            if let Ok(versions) = store.history(history_key.to_string()) {
+                return Ok(json_body(&version_list(versions)));
+            }
+        }
+    }
+    if let Some(version) = query.version {
+        let value = store.get_version(key, version).map_err(value_error)?;
+        return Ok(Response::builder()
             .header("Content-Type", value.mime_type)
-            .body(value.data)),
+            .header("X-Lucid-Version", version)
+            .body(Body::from(value.data.to_bytes())));
+    }
+    if let Some(field) = query.field {
+        let value = store.hget(key, &field).map_err(value_error)?;
+        return Ok(Response::builder()
+            .header("Content-Type", "text/plain")
+            .body(Body::from(value)));
+    }
+    if let Some(path) = query.path {
+        let value = store.get(key).ok_or_else(|| reject::custom(Error::KeyNotFound))?;
+        let document: serde_json::Value = serde_json::from_slice(&value.data.to_bytes())
+            .map_err(|_| value_error(kvstore::Error::NotJson))?;
+        return match document::select(&document, &path) {
+            Ok(Some(selected)) => Ok(json_body(selected)),
+            Ok(None) => Err(reject::custom(Error::PathNotFound)),
+            Err(e) => Err(value_error(kvstore::Error::Patch { source: e })),
+        };
+    }
+    if let Some(member) = query.member {
+        let is_member = store.sismember(key, &member).map_err(value_error)?;
+        return Ok(json_body(&is_member));
+    }
+    if !intersect.is_empty() {
+        let mut keys = intersect;
+        keys.insert(0, key);
+        return Ok(json_body(&store.sinter(&keys).map_err(value_error)?));
+    }
+    if query.min.is_some() || query.max.is_some() {
+        let members = store
+            .zrange_by_score(
+                key,
+                query.min.unwrap_or(f64::NEG_INFINITY),
+                query.max.unwrap_or(f64::INFINITY),
+            )
+            .map_err(value_error)?;
+        return Ok(json_body(&scored_members(members)));
+    }
+    if query.start.is_some() || query.stop.is_some() {
+        let (start, stop) = (query.start.unwrap_or(0), query.stop.unwrap_or(-1));
+        if store.data_type(&key) == Some(DataType::SortedSet) {
+            let members = store.zrange(key, start, stop).map_err(value_error)?;
+            return Ok(json_body(&scored_members(members)));
+        }
+        return Ok(json_body(&store.range(key, start, stop).map_err(value_error)?));
+    }
+    // Malformed and multiple ranges are ignored, the whole value is returned instead
+    if let Some(byte_range) = range.as_deref().and_then(parse_byte_range) {
+        let (value, len, range) = store
+            .get_range(key, |len| resolve_byte_range(byte_range, len))
+            .ok_or_else(|| reject::custom(Error::KeyNotFound))?;
+        return Ok(match range {
+            Some(range) => Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("Content-Type", value.mime_type)
+                .header("Accept-Ranges", "bytes")
+                .header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+                )
+                .body(Body::from(value.data.to_bytes())),
+            None => Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{}", len))
+                .body(Body::empty()),
+        });
+    }
+    // Large values are sent chunk by chunk, without being decrypted or copied at once
+    match store.get_chunks(key) {
+        Some((value, mut chunks)) => {
+            // A single chunk is sent as a whole, so the response can still be compressed
+            let size = chunks.size();
+            let body = match size {
+                size if size <= CHUNK_SIZE => Body::from(chunks.next().unwrap_or_default()),
+                _ => Body::wrap_stream(stream::iter(chunks.map(Ok::<_, Infallible>))),
+            };
+            Ok(Response::builder()
+                .header("Content-Type", value.mime_type)
+                .header("Content-Length", size)
+                .header("Accept-Ranges", "bytes")
+                .header("X-Lucid-Version", value.update_count)
+                .body(body))
+        }
         None => Err(reject::custom(Error::KeyNotFound))
     }
 }
 
+// Parses a single "bytes=start-end", "bytes=start-" or "bytes=-suffix" range
+fn parse_byte_range(header: &str) -> Option<(Option<usize>, Option<usize>)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let separator = spec.find('-')?;
+    let bound = |value: &str| match value.trim() {
+        "" => Some(None),
+        value => value.parse::<usize>().ok().map(Some),
+    };
+    match (bound(&spec[..separator])?, bound(&spec[separator + 1..])?) {
+        (None, None) => None,
+        (Some(start), Some(end)) if start > end => None,
+        byte_range => Some(byte_range),
+    }
+}
+
+fn resolve_byte_range(byte_range: (Option<usize>, Option<usize>), len: usize) -> Option<Range<usize>> {
+    match byte_range {
+        (Some(start), end) => Some(start..end.map_or(len, |end| (end + 1).min(len))),
+        (None, Some(suffix)) => Some(len.saturating_sub(suffix)..len),
+        (None, None) => None,
+    }
+}
+
+async fn check_patch_format(content_type: String) -> Result<PatchFormat, Rejection> {
+    match content_type.split(';').next().unwrap_or_default().trim() {
+        "application/json-patch+json" => Ok(PatchFormat::JsonPatch),
+        "application/merge-patch+json" => Ok(PatchFormat::MergePatch),
+        _ => Err(reject::not_found()),
+    }
+}
+
+async fn patch_document(
+    format: PatchFormat,
+    config: Arc<RwLock<Configuration>>,
+    namespaces: Arc<Namespaces>,
+    namespace: Arc<Namespace>,
+    key: String,
+    identity: Identity,
+    body: Bytes,
+) -> Result<impl Reply, Rejection> {
+    let store = &namespace.store;
+    let patch_error = |source| value_error(kvstore::Error::Patch { source });
+    let check = |kv_element: &KvElement, replaced: Option<&KvElement>| {
+        check_growth(&config, &namespaces, &namespace, kv_element, replaced)
+    };
+    let limits = Limits {
+        owner: Some(&identity.name),
+        check: &check,
+        ..Default::default()
+    };
+    // Room is made for the size of the patch, the limits still bound what it adds
+    evict(&namespace, &key, body.len() as u64);
+    let document = match format {
+        PatchFormat::JsonPatch => {
+            let operations = document::parse_patch(&body).map_err(patch_error)?;
+            store.update_json(key, &limits, |document| {
+                document::apply_patch(document, &operations)
+            })
+        }
+        PatchFormat::MergePatch => {
+            let patch = document::parse_merge_patch(&body).map_err(patch_error)?;
+            store.update_json(key, &limits, |document| {
+                document::merge_patch(document, &patch);
+                Ok(())
+            })
+        }
+    }
+    .map_err(value_error)?;
+    Ok(warp::reply::json(&document))
+}
+
+fn json_body<T: serde::Serialize>(value: &T) -> Result<Response<Body>, warp::http::Error> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+}
+
+fn version_list(versions: Vec<KvElement>) -> Vec<VersionInfo> {
+    versions
+        .into_iter()
+        .enumerate()
+        .map(|(index, version)| VersionInfo {
+            version: version.update_count,
+            current: index == 0,
+            updated_at: version.updated_at.to_rfc3339(),
+            size: version.size,
+            mime_type: version.mime_type,
+        })
+        .collect()
+}
+
+fn scored_members(members: Vec<(String, f64)>) -> Vec<ScoredMember> {
+    members
+        .into_iter()
+        .map(|(member, score)| ScoredMember { member, score })
+        .collect()
+}
+
 async fn find_key(store: Arc<KvStore>, key: String) -> Result<impl Reply, Rejection> {
-    match store.get(key) {
-        Some(value) => Ok(Response::builder()
+    match store.get_chunks(key) {
+        Some((value, chunks)) => Ok(Response::builder()
             .status(StatusCode::OK)
             .header("Content-Type", value.mime_type)
-            .header("Content-Length", value.data.len())
+            .header("Content-Length", chunks.size())
             .header("Last-Modified", value.updated_at.format("%a, %d %b %Y %T GMT").to_string())
             .body("")
             .unwrap()),
@@ -289,69 +1292,322 @@ async fn find_key(store: Arc<KvStore>, key: String) -> Result<impl Reply, Reject
     }
 }
 
-async fn delete_key(store: Arc<KvStore>, key: String) -> Result<impl Reply, Rejection> {
-    match store.get(key.clone()) {
-        Some(_) => {
-            (*store).drop(key);
-            Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                message: "The specified key and it's data was successfully deleted.".to_string(),
-            }), StatusCode::NO_CONTENT))
-        }
-        None => Err(reject::custom(Error::KeyNotFound))
+async fn delete_key(
+    config: Arc<RwLock<Configuration>>,
+    store: Arc<KvStore>,
+    key: String,
+) -> Result<impl Reply, Rejection> {
+    let deleted = match trash_retention(&config) {
+        Some(retention) => store.trash(key, retention),
+        None => (*store).drop(key),
+    };
+    match deleted {
+        true => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
+            message: "The specified key and it's data was successfully deleted.".to_string(),
+        }), StatusCode::NO_CONTENT)),
+        false => Err(reject::custom(Error::KeyNotFound))
+    }
+}
+
+fn trash_retention(config: &RwLock<Configuration>) -> Option<chrono::Duration> {
+    match config.read().unwrap().store.trash_retention {
+        0 => None,
+        retention => Some(chrono::Duration::seconds(retention as i64)),
     }
 }
 
-#[derive(Debug, Deserialize)]
+fn list_trash(namespace: Arc<Namespace>, query: PrefixQuery) -> impl Reply {
+    let keys: Vec<TrashedKey> = namespace
+        .store
+        .trashed(&query.prefix)
+        .into_iter()
+        .map(|(key, tombstone)| TrashedKey {
+            key,
+            deleted_at: tombstone.deleted_at.to_rfc3339(),
+            purge_at: tombstone.purge_at.to_rfc3339(),
+            size: tombstone.kv_element.size,
+            mime_type: tombstone.kv_element.mime_type,
+        })
+        .collect();
+    warp::reply::json(&keys)
+}
+
+fn empty_trash(namespace: Arc<Namespace>, query: PrefixQuery) -> impl Reply {
+    warp::reply::json(&KeyList {
+        keys: namespace.store.purge_prefix(&query.prefix),
+    })
+}
+
+async fn purge_key(namespace: Arc<Namespace>, key: String) -> Result<impl Reply, Rejection> {
+    match namespace.store.purge(&key) {
+        true => Ok(warp::reply::with_status(
+            warp::reply::json(&JsonMessage {
+                message: "The specified key was successfully purged.".to_string(),
+            }),
+            StatusCode::NO_CONTENT,
+        )),
+        false => Err(reject::custom(Error::KeyNotFound)),
+    }
+}
+
+// The restored value is charged to its owner again
+async fn undelete_key(
+    config: Arc<RwLock<Configuration>>,
+    namespaces: Arc<Namespaces>,
+    namespace: Arc<Namespace>,
+    key: String,
+    patch_value: PatchValue,
+) -> Result<impl Reply, Rejection> {
+    if patch_value.operation.to_lowercase() != "restore" {
+        return Err(reject::custom(Error::InvalidOperation {
+            operation: patch_value.operation,
+        }));
+    }
+    let check = |kv_element: &KvElement, replaced: Option<&KvElement>| {
+        check_growth(&config, &namespaces, &namespace, kv_element, replaced)
+    };
+    let limits = Limits {
+        check: &check,
+        ..Default::default()
+    };
+    namespace.store.undelete(key, &limits).map_err(value_error)?;
+    Ok(warp::reply::json(&JsonMessage {
+        message: "The specified key was successfully restored.".to_string(),
+    }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct PatchValue {
     operation: String,
     value: Option<String>,
+    values: Option<Vec<String>>,
+    field: Option<String>,
+    fields: Option<BTreeMap<String, String>>,
+    score: Option<f64>,
+    scores: Option<BTreeMap<String, f64>>,
+    initial: Option<String>,
+    start: Option<i64>,
+    stop: Option<i64>,
+    timeout: Option<u64>,
+    version: Option<i32>,
+    // Owner token of the lease, to unlock a key
+    token: Option<String>,
 }
+
+#[derive(Deserialize)]
+struct GetQuery {
+    start: Option<i64>,
+    stop: Option<i64>,
+    field: Option<String>,
+    member: Option<String>,
+    path: Option<String>,
+    min: Option<f64>,
+    max: Option<f64>,
+    version: Option<i32>,
+}
+
+enum PatchFormat {
+    JsonPatch,
+    MergePatch,
+}
+
+#[derive(Serialize)]
+struct ScoredMember {
+    member: String,
+    score: f64,
+}
+
+#[derive(Serialize)]
+struct SetMessage {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct HashMessage {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ListMessage {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CounterMessage {
+    message: String,
+    value: i64,
+}
+
+#[derive(Serialize)]
+struct VersionMessage {
+    message: String,
+    version: i32,
+}
+
+#[derive(Deserialize)]
+struct LockQuery {
+    ttl: Option<u64>,
+    wait: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct LeaseMessage {
+    message: String,
+    // Left out when the lease is described to someone else than its owner
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    fencing_token: u64,
+    expire_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    waiters: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct TrashedKey {
+    key: String,
+    deleted_at: String,
+    purge_at: String,
+    size: usize,
+    mime_type: String,
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    version: i32,
+    current: bool,
+    updated_at: String,
+    size: usize,
+    mime_type: String,
+}
+
 async fn patch_key(
-    store: Arc<KvStore>,
+    event_tx: Arc<broadcast::Sender<SseMessage>>,
+    config: Arc<RwLock<Configuration>>,
+    namespaces: Arc<Namespaces>,
+    namespace: Arc<Namespace>,
     key: String,
+    identity: Identity,
     patch_value: PatchValue,
 ) -> Result<impl Reply, Rejection> {
+    let store = &namespace.store;
+    let operation = patch_value.operation.to_lowercase();
+    let check = |kv_element: &KvElement, replaced: Option<&KvElement>| {
+        check_growth(&config, &namespaces, &namespace, kv_element, replaced)
+    };
+    let limits = Limits {
+        owner: Some(&identity.name),
+        check: &check,
+        max_collection_len: config.read().unwrap().store.max_collection_len,
+    };
+    // The request bounds what a write can add, room is made for it beforehand
+    if matches!(
+        operation.as_str(),
+        "append" | "increment" | "decrement" | "lpush" | "rpush" | "hset" | "hincrby" | "sadd"
+            | "zadd" | "zincrby"
+    ) {
+        let needed = serde_json::to_vec(&patch_value).unwrap().len() as u64;
+        evict(&namespace, &key, needed);
+    }
+    match operation.as_str() {
+        "increment" | "decrement" => {
+            return update_counter(store, &limits, key, &operation, &patch_value)
+        }
+        "lpush" | "rpush" | "lpop" | "rpop" | "blpop" | "brpop" | "ltrim" => {
+            return update_list(
+                &event_tx,
+                &config,
+                &namespace,
+                &limits,
+                key,
+                &operation,
+                patch_value,
+            )
+            .await
+        }
+        "hset" | "hdel" | "hincrby" => {
+            return update_hash(&event_tx, &namespace, &limits, key, &operation, patch_value)
+        }
+        "sadd" | "srem" | "zadd" | "zincrby" | "zrem" => {
+            return update_set(store, &limits, key, &operation, patch_value)
+        }
+        "restore" => {
+            let version = patch_value.version.ok_or_else(|| {
+                reject::custom(Error::MissingParameter {
+                    parameter: "version".to_string(),
+                })
+            })?;
+            let version = store.restore(key, version).map_err(value_error)?;
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&VersionMessage {
+                    message: "The specified version was successfully restored.".to_string(),
+                    version,
+                }),
+                StatusCode::OK,
+            ));
+        }
+        "append" => {
+            let value = patch_value.value.ok_or_else(|| {
+                reject::custom(Error::MissingParameter {
+                    parameter: "value".to_string(),
+                })
+            })?;
+            let length = store
+                .append(key, value.as_bytes(), &limits)
+                .map_err(value_error)?;
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&ListMessage {
+                    message: "The value was successfully appended.".to_string(),
+                    length: Some(length),
+                    value: None,
+                }),
+                StatusCode::OK,
+            ));
+        }
+        _ => {}
+    }
     if let Some(_) = store.get(key.clone()) {
-        match patch_value.operation.to_lowercase().as_str() {
+        match operation.as_str() {
+            // Deprecated, kept as a lease named after the key so the lock expires
+            // and only its owner can unlock it. Its fencing token does not allow
+            // writes to the locked key.
             "lock" => {
-                match store.switch_lock(key.to_string(), true) {
-                    true => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key was successfully locked.".to_string(),
-                    }), StatusCode::OK)),
-                    false => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key is already locked.".to_string(),
-                    }), StatusCode::CONFLICT))
-                }
+                let lease = namespace
+                    .leases
+                    .acquire(&key, lease_ttl(&config, None)?)
+                    .map_err(|source| reject::custom(Error::LockFailed { source }))?;
+                store.switch_lock(key, true);
+                Ok(warp::reply::with_status(warp::reply::json(&lease_reply(
+                    "The specified key was successfully locked.",
+                    lease,
+                    true,
+                )), StatusCode::OK))
             }
             "unlock" => {
-                match store.switch_lock(key.to_string(), false) {
-                    true => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key was successfully unlocked.".to_string(),
-                    }), StatusCode::OK)),
-                    false => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key is not currently locked.".to_string(),
-                    }), StatusCode::CONFLICT))
-                }
-            }
-            "increment" => {
-                match store.increment_or_decrement(key.to_string(), 1.0) {
-                    true => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key was successfully incremented.".to_string(),
-                    }), StatusCode::OK)),
-                    false => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key is not a valid numeric value.".to_string(),
-                    }), StatusCode::BAD_REQUEST))
-                }
-            }
-            "decrement" => {
-                match store.increment_or_decrement(key.to_string(), -1.0) {
-                    true => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key was successfully decremented.".to_string(),
-                    }), StatusCode::OK)),
-                    false => Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
-                        message: "The specified key is not a valid numeric value.".to_string(),
-                    }), StatusCode::BAD_REQUEST))
-                }
+                let token = patch_value.token.ok_or_else(|| {
+                    reject::custom(Error::MissingParameter {
+                        parameter: "token".to_string(),
+                    })
+                })?;
+                namespace
+                    .leases
+                    .release(&key, &token)
+                    .map_err(|source| reject::custom(Error::LockFailed { source }))?;
+                store.switch_lock(key, false);
+                Ok(warp::reply::with_status(warp::reply::json(&JsonMessage {
+                    message: "The specified key was successfully unlocked.".to_string(),
+                }), StatusCode::OK))
             }
             "ttl" => {
                 match patch_value.value {
@@ -386,25 +1642,765 @@ async fn patch_key(
     }
 }
 
+fn update_counter(
+    store: &KvStore,
+    limits: &Limits<'_>,
+    key: String,
+    operation: &str,
+    patch_value: &PatchValue,
+) -> Result<WithStatus<Json>, Rejection> {
+    let parse = |value: &Option<String>| match value {
+        Some(value) => value
+            .trim()
+            .parse::<i64>()
+            .map(Some)
+            .map_err(|_| reject::custom(Error::InvalidCounterValue)),
+        None => Ok(None),
+    };
+    let mut delta = parse(&patch_value.value)?.unwrap_or(1);
+    if operation == "decrement" {
+        delta = delta
+            .checked_neg()
+            .ok_or_else(|| reject::custom(Error::InvalidCounterValue))?;
+    }
+    let initial = parse(&patch_value.initial)?;
+    match store.increment(key, delta, initial, limits) {
+        Ok(value) => Ok(warp::reply::with_status(
+            warp::reply::json(&CounterMessage {
+                message: format!("The specified key was successfully {}ed.", operation),
+                value,
+            }),
+            StatusCode::OK,
+        )),
+        Err(e) => Err(value_error(e)),
+    }
+}
+
+async fn update_list(
+    event_tx: &broadcast::Sender<SseMessage>,
+    config: &RwLock<Configuration>,
+    namespace: &Namespace,
+    limits: &Limits<'_>,
+    key: String,
+    operation: &str,
+    patch_value: PatchValue,
+) -> Result<WithStatus<Json>, Rejection> {
+    let store = &namespace.store;
+    let front = matches!(operation, "lpush" | "lpop" | "blpop");
+    let message = match operation {
+        "lpush" | "rpush" => {
+            let mut values = patch_value.values.unwrap_or_default();
+            values.extend(patch_value.value);
+            if values.is_empty() {
+                return Err(reject::custom(Error::MissingParameter {
+                    parameter: "value".to_string(),
+                }));
+            }
+            let event = serde_json::to_string(&values).unwrap();
+            let length = store
+                .push(key.clone(), values, front, limits)
+                .map_err(value_error)?;
+            // Also wakes up the blocking pops waiting on this list
+            event_tx
+                .send(SseMessage::Update {
+                    key: namespace.event_name(&key),
+                    value: event,
+                })
+                .ok();
+            ListMessage {
+                message: "The values were successfully pushed.".to_string(),
+                length: Some(length),
+                value: None,
+            }
+        }
+        "lpop" | "rpop" => ListMessage {
+            message: "The value was successfully popped.".to_string(),
+            length: None,
+            value: Some(store.pop(key, front).map_err(value_error)?),
+        },
+        "blpop" | "brpop" => {
+            let max_timeout = config.read().unwrap().store.max_pop_timeout;
+            let timeout = Duration::from_secs(patch_value.timeout.unwrap_or(0).min(max_timeout));
+            match blocking_pop(event_tx, namespace, key, front, timeout).await {
+                Ok(Some(value)) => ListMessage {
+                    message: "The value was successfully popped.".to_string(),
+                    length: None,
+                    value: Some(value),
+                },
+                Ok(None) => return Err(reject::custom(Error::KeyNotFound)),
+                Err(e) => return Err(value_error(e)),
+            }
+        }
+        _ => {
+            let (start, stop) = match (patch_value.start, patch_value.stop) {
+                (Some(start), Some(stop)) => (start, stop),
+                (None, _) => {
+                    return Err(reject::custom(Error::MissingParameter {
+                        parameter: "start".to_string(),
+                    }))
+                }
+                (_, None) => {
+                    return Err(reject::custom(Error::MissingParameter {
+                        parameter: "stop".to_string(),
+                    }))
+                }
+            };
+            ListMessage {
+                message: "The list was successfully trimmed.".to_string(),
+                length: Some(store.trim(key, start, stop).map_err(value_error)?),
+                value: None,
+            }
+        }
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&message),
+        StatusCode::OK,
+    ))
+}
+
+fn update_hash(
+    event_tx: &broadcast::Sender<SseMessage>,
+    namespace: &Namespace,
+    limits: &Limits,
+    key: String,
+    operation: &str,
+    patch_value: PatchValue,
+) -> Result<WithStatus<Json>, Rejection> {
+    let store = &namespace.store;
+    let missing = |parameter: &str| {
+        reject::custom(Error::MissingParameter {
+            parameter: parameter.to_string(),
+        })
+    };
+    let send = |field: String, value: Option<String>| {
+        event_tx
+            .send(SseMessage::FieldUpdate {
+                key: namespace.event_name(&key),
+                field,
+                value,
+            })
+            .ok();
+    };
+    let message = match operation {
+        "hset" => {
+            let mut fields = patch_value.fields.unwrap_or_default();
+            if let (Some(field), Some(value)) = (patch_value.field, patch_value.value) {
+                fields.insert(field, value);
+            }
+            if fields.is_empty() {
+                return Err(missing("fields"));
+            }
+            let added = store
+                .hset(key.clone(), fields.clone(), limits)
+                .map_err(value_error)?;
+            for (field, value) in fields {
+                send(field, Some(value));
+            }
+            HashMessage {
+                message: "The fields were successfully set.".to_string(),
+                count: Some(added),
+                value: None,
+            }
+        }
+        "hdel" => {
+            let field = patch_value.field.ok_or_else(|| missing("field"))?;
+            let removed = store
+                .hdel(key.clone(), std::slice::from_ref(&field))
+                .map_err(value_error)?;
+            if removed > 0 {
+                send(field, None);
+            }
+            HashMessage {
+                message: "The field was successfully deleted.".to_string(),
+                count: Some(removed),
+                value: None,
+            }
+        }
+        _ => {
+            let field = patch_value.field.ok_or_else(|| missing("field"))?;
+            let delta = match patch_value.value {
+                Some(value) => value
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| reject::custom(Error::InvalidCounterValue))?,
+                None => 1,
+            };
+            let value = store
+                .hincrby(key.clone(), field.clone(), delta, limits)
+                .map_err(value_error)?;
+            send(field, Some(value.to_string()));
+            HashMessage {
+                message: "The field was successfully incremented.".to_string(),
+                count: None,
+                value: Some(value),
+            }
+        }
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&message),
+        StatusCode::OK,
+    ))
+}
+
+fn update_set(
+    store: &KvStore,
+    limits: &Limits,
+    key: String,
+    operation: &str,
+    patch_value: PatchValue,
+) -> Result<WithStatus<Json>, Rejection> {
+    let mut members = patch_value.values.unwrap_or_default();
+    members.extend(patch_value.value);
+    let missing = |parameter: &str| {
+        reject::custom(Error::MissingParameter {
+            parameter: parameter.to_string(),
+        })
+    };
+    let message = match operation {
+        "zadd" => {
+            let mut scores = patch_value.scores.unwrap_or_default();
+            if let Some(score) = patch_value.score {
+                scores.extend(members.into_iter().map(|member| (member, score)));
+            }
+            if scores.is_empty() {
+                return Err(missing("scores"));
+            }
+            SetMessage {
+                message: "The members were successfully added.".to_string(),
+                count: Some(store.zadd(key, scores, limits).map_err(value_error)?),
+                score: None,
+            }
+        }
+        "zincrby" => {
+            if members.len() != 1 {
+                return Err(missing("value"));
+            }
+            let delta = patch_value.score.unwrap_or(1.0);
+            SetMessage {
+                message: "The member was successfully incremented.".to_string(),
+                count: None,
+                score: Some(
+                    store
+                        .zincrby(key, members.remove(0), delta, limits)
+                        .map_err(value_error)?,
+                ),
+            }
+        }
+        _ if members.is_empty() => return Err(missing("value")),
+        "sadd" => SetMessage {
+            message: "The members were successfully added.".to_string(),
+            count: Some(store.sadd(key, members, limits).map_err(value_error)?),
+            score: None,
+        },
+        "srem" => SetMessage {
+            message: "The members were successfully removed.".to_string(),
+            count: Some(store.srem(key, &members).map_err(value_error)?),
+            score: None,
+        },
+        _ => SetMessage {
+            message: "The members were successfully removed.".to_string(),
+            count: Some(store.zrem(key, &members).map_err(value_error)?),
+            score: None,
+        },
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&message),
+        StatusCode::OK,
+    ))
+}
+
+// Retries the pop each time the list is updated, until the timeout or the server shutdown
+async fn blocking_pop(
+    event_tx: &broadcast::Sender<SseMessage>,
+    namespace: &Namespace,
+    key: String,
+    front: bool,
+    timeout: Duration,
+) -> Result<Option<String>, kvstore::Error> {
+    let mut event_rx = event_tx.subscribe();
+    let event_name = namespace.event_name(&key);
+    let deadline = time::Instant::now()
+        .checked_add(timeout)
+        .unwrap_or_else(time::Instant::now);
+    loop {
+        match namespace.store.pop(key.clone(), front) {
+            Ok(value) => return Ok(Some(value)),
+            Err(kvstore::Error::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        loop {
+            match time::timeout_at(deadline, event_rx.recv()).await {
+                Ok(Ok(SseMessage::Update { key, .. })) if key == event_name => break,
+                Ok(Ok(SseMessage::Update { .. })) | Ok(Ok(SseMessage::FieldUpdate { .. })) => {}
+                Ok(Err(broadcast::RecvError::Lagged(_))) => break,
+                Ok(Ok(SseMessage::Shutdown)) | Ok(Err(_)) | Err(_) => return Ok(None),
+            }
+        }
+    }
+}
+
+async fn acquire_lock(
+    config: Arc<RwLock<Configuration>>,
+    namespace: Arc<Namespace>,
+    name: String,
+    query: LockQuery,
+) -> Result<impl Reply, Rejection> {
+    let ttl = lease_ttl(&config, query.ttl)?;
+    let max_wait = config.read().unwrap().locks.max_wait;
+    let lease = match query.wait.unwrap_or(0).min(max_wait) {
+        0 => namespace.leases.acquire(&name, ttl),
+        wait => wait_for_lock(&namespace.leases, &name, ttl, Duration::from_secs(wait)).await,
+    }
+    .map_err(|source| reject::custom(Error::LockFailed { source }))?;
+    Ok(warp::reply::json(&lease_reply(
+        "The lock was successfully acquired.",
+        lease,
+        true,
+    )))
+}
+
+// Key locks end with their lease, they are lifted before the key is written to
+async fn check_fencing_token(
+    namespace: Arc<Namespace>,
+    key: String,
+    identity: Identity,
+    fencing_token: Option<u64>,
+) -> Result<(Arc<Namespace>, String, Identity), Rejection> {
+    if namespace.leases.get(&key).is_none() {
+        namespace.store.switch_lock(key.clone(), false);
+    }
+    if let Some(fencing_token) = fencing_token {
+        namespace
+            .leases
+            .check_fencing_token(&key, fencing_token)
+            .map_err(|source| reject::custom(Error::LockFailed { source }))?;
+    }
+    Ok((namespace, key, identity))
+}
+
+// Nothing releases an abandoned lease, so waiters also wake up when it expires
+async fn wait_for_lock(
+    leases: &Leases,
+    name: &str,
+    ttl: chrono::Duration,
+    wait: Duration,
+) -> Result<Lease, lease::Error> {
+    let (id, mut receiver) = match leases.acquire_or_queue(name, ttl) {
+        Acquire::Acquired(lease) => return Ok(lease),
+        Acquire::Queued { id, receiver } => (id, receiver),
+    };
+    let deadline = time::Instant::now()
+        .checked_add(wait)
+        .unwrap_or_else(time::Instant::now);
+    loop {
+        let wake_at = match leases.get(name) {
+            Some((lease, _)) => match (lease.expire_at - Utc::now()).to_std() {
+                Ok(remaining) if time::Instant::now() + remaining < deadline => {
+                    time::Instant::now() + remaining
+                }
+                Ok(_) => deadline,
+                Err(_) => time::Instant::now(),
+            },
+            None => deadline,
+        };
+        match time::timeout_at(wake_at, &mut receiver).await {
+            Ok(Ok(lease)) => return Ok(lease),
+            Err(_) if time::Instant::now() < deadline => {}
+            _ => break,
+        }
+    }
+    if !leases.cancel(name, id) {
+        if let Ok(lease) = receiver.try_recv() {
+            return Ok(lease);
+        }
+    }
+    Err(lease::Error::Held {
+        expire_at: leases
+            .get(name)
+            .map_or_else(Utc::now, |(lease, _)| lease.expire_at),
+    })
+}
+
+async fn renew_lock(
+    config: Arc<RwLock<Configuration>>,
+    namespace: Arc<Namespace>,
+    name: String,
+    token: Option<String>,
+    query: LockQuery,
+) -> Result<impl Reply, Rejection> {
+    let token = lock_token(token)?;
+    let ttl = lease_ttl(&config, query.ttl)?;
+    let lease = namespace
+        .leases
+        .renew(&name, &token, ttl)
+        .map_err(|source| reject::custom(Error::LockFailed { source }))?;
+    Ok(warp::reply::json(&lease_reply(
+        "The lock was successfully renewed.",
+        lease,
+        true,
+    )))
+}
+
+async fn release_lock(
+    namespace: Arc<Namespace>,
+    name: String,
+    token: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    let token = lock_token(token)?;
+    namespace
+        .leases
+        .release(&name, &token)
+        .map_err(|source| reject::custom(Error::LockFailed { source }))?;
+    Ok(warp::reply::json(&JsonMessage {
+        message: "The lock was successfully released.".to_string(),
+    }))
+}
+
+async fn find_lock(namespace: Arc<Namespace>, name: String) -> Result<impl Reply, Rejection> {
+    match namespace.leases.get(&name) {
+        Some((lease, waiters)) => {
+            let mut reply = lease_reply("The lock is currently held.", lease, false);
+            reply.waiters = Some(waiters);
+            Ok(warp::reply::json(&reply))
+        }
+        None => Err(reject::custom(Error::LockFailed {
+            source: lease::Error::NotHeld,
+        })),
+    }
+}
+
+fn lease_ttl(
+    config: &RwLock<Configuration>,
+    ttl: Option<u64>,
+) -> Result<chrono::Duration, Rejection> {
+    let locks = &config.read().unwrap().locks;
+    match ttl.unwrap_or(locks.default_ttl) {
+        ttl if ttl > 0 && ttl <= locks.max_ttl => Ok(chrono::Duration::seconds(ttl as i64)),
+        _ => Err(reject::custom(Error::InvalidExpiration {
+            reason: format!("the lease TTL must be between 1 and {} seconds", locks.max_ttl),
+        })),
+    }
+}
+
+fn lock_token(token: Option<String>) -> Result<String, Rejection> {
+    token.ok_or_else(|| {
+        reject::custom(Error::MissingParameter {
+            parameter: "x-lucid-lock-token".to_string(),
+        })
+    })
+}
+
+fn lease_reply(message: &str, lease: Lease, owner: bool) -> LeaseMessage {
+    LeaseMessage {
+        message: message.to_string(),
+        token: Some(lease.token).filter(|_| owner),
+        fencing_token: lease.fencing_token,
+        expire_at: lease.expire_at.to_rfc3339(),
+        waiters: None,
+    }
+}
+
+fn value_error(e: kvstore::Error) -> Rejection {
+    match e {
+        kvstore::Error::KeyNotFound => reject::custom(Error::KeyNotFound),
+        kvstore::Error::ValueTooLarge { max_limit } => {
+            reject::custom(Error::ValueSizeLimit { max_limit })
+        }
+        kvstore::Error::QuotaExceeded { max_bytes } => {
+            reject::custom(Error::QuotaExceeded { max_bytes })
+        }
+        kvstore::Error::NamespaceFull { max_bytes } => {
+            reject::custom(Error::NamespaceFull { max_bytes })
+        }
+        e => reject::custom(Error::UpdateValue { source: e }),
+    }
+}
+
+async fn reload_config(config: Arc<RwLock<Configuration>>) -> Result<impl Reply, Rejection> {
+    let report = reload_configuration(&config).map_err(reject::custom)?;
+    log_reload(&report);
+    let message = if report.restart_required.is_empty() {
+        "The configuration was successfully reloaded."
+    } else {
+        "The configuration was reloaded, some settings require a restart."
+    };
+    Ok(warp::reply::json(&ReloadMessage {
+        message: message.to_string(),
+        report,
+    }))
+}
+
+fn server_info(
+    namespaces: Arc<Namespaces>,
+    event_tx: Arc<broadcast::Sender<SseMessage>>,
+    config: Arc<RwLock<Configuration>>,
+    started_at: DateTime<Utc>,
+) -> impl Reply {
+    let config = config.read().unwrap();
+    let stats = namespaces.stats();
+    warp::reply::json(&json!({
+        "version": crate_version!(),
+        "pid": std::process::id(),
+        "started_at": started_at.to_rfc3339(),
+        "uptime_seconds": (Utc::now() - started_at).num_seconds(),
+        "store": {
+            "keys": stats.keys,
+            "stored_bytes": stats.stored_bytes(),
+            "memory_estimate_bytes": stats.memory_estimate(),
+            "encryption": config.encryption.enabled,
+            "namespaces": namespaces.list().len(),
+            "compression": {
+                "algorithm": config.store.compression.algorithm,
+                "values": stats.compression.values,
+                "stored_bytes": stats.compression.stored_bytes,
+                "original_bytes": stats.compression.original_bytes,
+                "ratio": stats.compression.ratio(),
+            },
+        },
+        "persistence": {
+            "enabled": config.persistence.enabled,
+            "location": config.persistence.location,
+        },
+        "replication": {
+            "role": "standalone",
+        },
+        "sse": {
+            "enabled": config.sse.enabled,
+            "clients": event_tx.receiver_count(),
+        },
+        "configuration": config.redacted(),
+    }))
+}
+
+fn readiness(namespaces: Arc<Namespaces>, config: Arc<RwLock<Configuration>>) -> impl Reply {
+    let config = config.read().unwrap();
+    let stats = namespaces.stats();
+    let mut checks = Vec::new();
+
+    // The store lives in memory and is usable as soon as it is created
+    checks.push(("store", Ok(format!("{} keys loaded", stats.keys))));
+
+    checks.push((
+        "persistence",
+        if config.persistence.enabled {
+            check_writable(&config.persistence.location)
+                .map(|_| format!("{} is writable", config.persistence.location))
+        } else {
+            Ok(String::from("disabled"))
+        },
+    ));
+
+    checks.push(("replication", Ok(String::from("standalone"))));
+
+    let memory_estimate = stats.memory_estimate();
+    checks.push((
+        "memory",
+        if config.health.memory_threshold == 0 {
+            Ok(format!("{} bytes used, no threshold", memory_estimate))
+        } else if memory_estimate < config.health.memory_threshold {
+            Ok(format!(
+                "{} of {} bytes used",
+                memory_estimate, config.health.memory_threshold
+            ))
+        } else {
+            Err(format!(
+                "{} bytes used, over the {} bytes threshold",
+                memory_estimate, config.health.memory_threshold
+            ))
+        },
+    ));
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks: serde_json::Map<String, serde_json::Value> = checks
+        .into_iter()
+        .map(|(name, result)| {
+            let check = match result {
+                Ok(detail) => json!({ "status": "pass", "detail": detail }),
+                Err(detail) => json!({ "status": "fail", "detail": detail }),
+            };
+            (name.to_string(), check)
+        })
+        .collect();
+    warp::reply::with_status(
+        warp::reply::json(&json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "checks": checks,
+        })),
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+    )
+}
+
+fn check_writable(location: &str) -> Result<(), String> {
+    let probe = std::path::Path::new(location).join(format!(".lucid-ready-{}", std::process::id()));
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {}", location, e))
+}
+
 async fn verify_auth(
     auth_header: Option<String>,
+    api_key_header: Option<String>,
+    connection: Option<ConnectionInfo>,
     config: Arc<RwLock<Configuration>>,
-) -> Result<(), Rejection> {
+    authenticator: Arc<Authenticator>,
+) -> Result<Identity, Rejection> {
     let config = config.read().unwrap();
     if config.authentication.enabled {
-        if let Some(auth_header) = auth_header {
-            if let Ok(_bearer) = jsonwebtoken::decode::<Claims>(
+        if let Some(identity) = connection.and_then(|connection| {
+            let certificate = connection.client_certificate.as_ref()?;
+            authenticator
+                .verify_client_certificate(&config.authentication, certificate)
+                .map_err(|e| debug!("{} ({})", e, connection.remote_addr))
+                .ok()
+        }) {
+            Ok(identity)
+        } else if let Some(api_key) = api_key_header.as_deref().or_else(|| {
+            auth_header
+                .as_deref()
+                .filter(|header| header.starts_with("ApiKey "))
+                .map(|header| header.trim_start_matches("ApiKey "))
+        }) {
+            authenticator
+                .verify_api_key(&config.authentication, api_key)
+                .map_err(|_| reject::custom(Error::InvalidApiKey))
+        } else if let Some(auth_header) = auth_header {
+            match authenticator.verify(
+                &config.authentication,
                 auth_header.trim_start_matches("Bearer "),
-                config.authentication.secret_key.as_ref(),
-                &Validation::default(),
             ) {
-                Ok(())
-            } else {
-                Err(reject::custom(Error::InvalidJwtToken))
+                Ok(claims) => Ok(Identity::from(claims)),
+                Err(e) => {
+                    debug!("Rejected JWT token: {}", e);
+                    Err(reject::custom(Error::InvalidJwtToken))
+                }
             }
         } else {
             Err(reject::custom(Error::MissingAuthHeader))
         }
+    } else {
+        Ok(Identity::anonymous())
+    }
+}
+
+async fn check_rate_limit(
+    identity: Identity,
+    scope: Scope,
+    remote_addr: Option<SocketAddr>,
+    config: Arc<RwLock<Configuration>>,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<Identity, Rejection> {
+    let config = config.read().unwrap();
+    // Without authentication everyone is anonymous, so clients are told apart by address
+    let client = if config.authentication.enabled {
+        identity.name.clone()
+    } else {
+        remote_addr
+            .map(|remote_addr| remote_addr.ip().to_string())
+            .unwrap_or_else(|| String::from("local"))
+    };
+    match rate_limiter.check(&config.rate_limit, scope, &client) {
+        Ok(()) => Ok(identity),
+        Err(wait) => {
+            debug!("{} exceeded the {:?} rate limit", client, scope);
+            Err(reject::custom(Error::RateLimited {
+                retry_after: wait.as_secs_f64().ceil().max(1.0) as u64,
+            }))
+        }
+    }
+}
+
+async fn authorize(identity: Identity, scope: Scope) -> Result<Identity, Rejection> {
+    if identity.allows(scope) {
+        Ok(identity)
+    } else {
+        debug!("{} is missing the {:?} scope", identity.name, scope);
+        Err(reject::custom(Error::MissingScope { scope }))
+    }
+}
+
+async fn check_request_size(
+    content_length: Option<u64>,
+    config: Arc<RwLock<Configuration>>,
+) -> Result<(), Rejection> {
+    let limit = config.read().unwrap().http.request_size_limit;
+    match content_length {
+        Some(content_length) if content_length <= limit => Ok(()),
+        Some(_) => Err(reject::custom(Error::RequestSizeLimit { limit })),
+        None => Err(reject::custom(Error::MissingContentLength)),
+    }
+}
+
+// Responses are buffered to be compressed, so streamed bodies, whose size is not
+// known upfront, and event streams are always left as is
+async fn compress_response(
+    reply: impl Reply,
+    accept_encoding: Option<String>,
+    config: Arc<RwLock<Configuration>>,
+) -> Result<Response<Body>, Infallible> {
+    let response = reply.into_response();
+    let encoding = match accept_encoding.as_deref().and_then(ContentEncoding::negotiate) {
+        Some(encoding) if config.read().unwrap().http.compression => encoding,
+        _ => return Ok(response),
+    };
+    let headers = response.headers();
+    let streamed = headers
+        .get("content-type")
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"));
+    if streamed
+        || HttpBody::size_hint(response.body()).exact().is_none()
+        || headers.contains_key("content-encoding")
+        || response.status() == StatusCode::PARTIAL_CONTENT
+    {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Unable to read the response body: {}", e);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return Ok(response);
+        }
+    };
+    if body.len() < MIN_COMPRESSED_RESPONSE {
+        return Ok(Response::from_parts(parts, Body::from(body)));
+    }
+    match encoding.encode(&body) {
+        Ok(encoded) => {
+            parts.headers.remove("content-length");
+            parts
+                .headers
+                .insert("content-encoding", HeaderValue::from_static(encoding.name()));
+            parts
+                .headers
+                .insert("vary", HeaderValue::from_static("accept-encoding"));
+            Ok(Response::from_parts(parts, Body::from(encoded)))
+        }
+        Err(e) => {
+            warn!("Unable to compress a response: {}", e);
+            Ok(Response::from_parts(parts, Body::from(body)))
+        }
+    }
+}
+
+async fn check_metrics(config: Arc<RwLock<Configuration>>) -> Result<(), Rejection> {
+    let config = config.read().unwrap();
+    if config.metrics.enabled {
+        Ok(())
+    } else {
+        Err(reject::not_found())
+    }
+}
+
+async fn check_metrics_public(config: Arc<RwLock<Configuration>>) -> Result<(), Rejection> {
+    let config = config.read().unwrap();
+    if config.metrics.require_auth {
+        Err(reject::not_found())
     } else {
         Ok(())
     }
@@ -432,13 +2428,40 @@ fn sse_event_stream(
     event_rx: broadcast::Receiver<SseMessage>,
 ) -> impl Stream<Item = Result<impl ServerSentEvent + Send + 'static, warp::Error>> + Send + 'static
 {
-    event_rx.filter_map(move |msg| match msg {
-        Ok(msg) => Some(Ok((warp::sse::event(msg.key), warp::sse::data(msg.value)))),
-        Err(broadcast::RecvError::Lagged(lag)) => {
-            warn!("SSE stream lagged, {} events lost", lag);
-            None
+    stream::unfold(Some(event_rx), |event_rx| async {
+        let mut event_rx = event_rx?;
+        loop {
+            match event_rx.recv().await {
+                Ok(SseMessage::Update { key, value }) => {
+                    return Some((
+                        Ok((warp::sse::event(key), warp::sse::data(value))),
+                        Some(event_rx),
+                    ))
+                }
+                Ok(SseMessage::FieldUpdate { key, field, value }) => {
+                    let data = json!({ "field": field, "value": value }).to_string();
+                    return Some((
+                        Ok((warp::sse::event(key), warp::sse::data(data))),
+                        Some(event_rx),
+                    ));
+                }
+                // Let the client know why the stream ends, then close it
+                Ok(SseMessage::Shutdown) => {
+                    return Some((
+                        Ok((
+                            warp::sse::event(String::from("shutdown")),
+                            warp::sse::data(String::from("The Lucid server is shutting down.")),
+                        )),
+                        None,
+                    ))
+                }
+                Err(broadcast::RecvError::Lagged(lag)) => {
+                    warn!("SSE stream lagged, {} events lost", lag);
+                    metrics::SSE_LAGGED_EVENTS.inc_by(lag as i64);
+                }
+                Err(broadcast::RecvError::Closed) => return None,
+            }
         }
-        Err(broadcast::RecvError::Closed) => unreachable!(),
     })
 }
 
@@ -446,50 +2469,130 @@ async fn process_error(err: Rejection) -> Result<impl Reply, Rejection> {
     if let Some(err) = err.find::<Error>() {
         let code = match err {
             Error::MissingBody => StatusCode::BAD_REQUEST,
+            Error::ReadBody => StatusCode::BAD_REQUEST,
             Error::MissingParameter { .. } => StatusCode::BAD_REQUEST,
             Error::MissingAuthHeader => StatusCode::UNAUTHORIZED,
             Error::KeyNotFound => StatusCode::NOT_FOUND,
+            Error::PathNotFound => StatusCode::NOT_FOUND,
             Error::InvalidOperation { .. } => StatusCode::BAD_REQUEST,
             Error::InvalidJwtToken => StatusCode::UNAUTHORIZED,
+            Error::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            Error::MissingScope { .. } => StatusCode::FORBIDDEN,
             Error::ValueSizeLimit { .. } => StatusCode::BAD_REQUEST,
+            Error::RequestSizeLimit { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::MissingContentLength => StatusCode::LENGTH_REQUIRED,
+            Error::MissingConfigFile => StatusCode::CONFLICT,
+            Error::LoadConfiguration { .. } => StatusCode::BAD_REQUEST,
+            Error::InvalidAuthentication { .. } => StatusCode::BAD_REQUEST,
+            Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            Error::NamespaceFull { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            Error::InvalidExpiration { .. } => StatusCode::BAD_REQUEST,
+            Error::InvalidCounterValue => StatusCode::BAD_REQUEST,
+            Error::UpdateValue { source } => match source {
+                kvstore::Error::Locked => StatusCode::FORBIDDEN,
+                kvstore::Error::WrongType => StatusCode::CONFLICT,
+                kvstore::Error::FieldNotFound => StatusCode::NOT_FOUND,
+                kvstore::Error::VersionNotFound => StatusCode::NOT_FOUND,
+                kvstore::Error::KeyExists => StatusCode::CONFLICT,
+                kvstore::Error::NotJson => StatusCode::CONFLICT,
+                kvstore::Error::Patch { source } => match source {
+                    document::Error::PathNotFound { .. } => StatusCode::CONFLICT,
+                    document::Error::TestFailed { .. } => StatusCode::CONFLICT,
+                    _ => StatusCode::BAD_REQUEST,
+                },
+                _ => StatusCode::BAD_REQUEST,
+            },
+            Error::InvalidNamespace { source } => match source {
+                namespace::Error::InvalidName { .. } => StatusCode::BAD_REQUEST,
+                namespace::Error::AlreadyExists { .. } => StatusCode::CONFLICT,
+                namespace::Error::NotFound { .. } => StatusCode::NOT_FOUND,
+                namespace::Error::InvalidEncryptionKey => StatusCode::BAD_REQUEST,
+            },
+            Error::LockFailed { source } => match source {
+                lease::Error::Held { .. } => StatusCode::CONFLICT,
+                lease::Error::NotHeld => StatusCode::NOT_FOUND,
+                lease::Error::NotOwner => StatusCode::FORBIDDEN,
+                lease::Error::Stale { .. } => StatusCode::CONFLICT,
+            },
         };
         let json = warp::reply::json(&JsonMessage {
             message: err.to_string(),
         });
-        Ok(warp::reply::with_status(json, code))
+        let mut response = warp::reply::with_status(json, code).into_response();
+        if let Error::RateLimited { retry_after } = err {
+            response
+                .headers_mut()
+                .insert("Retry-After", (*retry_after).into());
+        }
+        Ok(response)
     } else if let Some(_) = err.find::<reject::MethodNotAllowed>() {
         let code = StatusCode::METHOD_NOT_ALLOWED;
         let json = warp::reply::json(&JsonMessage {
             message: "Method not allowed.".to_string(),
         });
-        Ok(warp::reply::with_status(json, code))
+        Ok(warp::reply::with_status(json, code).into_response())
     } else if let Some(_) = err.find::<reject::PayloadTooLarge>() {
         let code = StatusCode::METHOD_NOT_ALLOWED;
         let json = warp::reply::json(&JsonMessage {
             message: "Request payload is too long.".to_string(), // TODO: find a way to format the limit into this string
         });
-        Ok(warp::reply::with_status(json, code))
+        Ok(warp::reply::with_status(json, code).into_response())
     } else {
         Err(err)
     }
 }
 
 #[derive(Debug, Snafu)]
-enum Error {
+pub enum Error {
     #[snafu(display("Missing request body."))]
     MissingBody,
+    #[snafu(display("Unable to read the request body."))]
+    ReadBody,
     #[snafu(display("Missing \"{}\" parameter.", parameter))]
     MissingParameter { parameter: String },
     #[snafu(display("Missing Authorization header."))]
     MissingAuthHeader,
     #[snafu(display("The specified key does not exist."))]
     KeyNotFound,
+    #[snafu(display("The specified path does not exist in the document."))]
+    PathNotFound,
     #[snafu(display("Invalid Operation \"{}\".", operation))]
     InvalidOperation { operation: String },
     #[snafu(display("Invalid JWT token in Authorization header."))]
     InvalidJwtToken,
+    #[snafu(display("Invalid API key."))]
+    InvalidApiKey,
+    #[snafu(display("The \"{:?}\" scope is required for this operation.", scope))]
+    MissingScope { scope: Scope },
     #[snafu(display("The maximum allowed value size is {} bytes.", max_limit))]
     ValueSizeLimit { max_limit: u64 },
+    #[snafu(display("The maximum allowed request size is {} bytes.", limit))]
+    RequestSizeLimit { limit: u64 },
+    #[snafu(display("Missing Content-Length header."))]
+    MissingContentLength,
+    #[snafu(display("The server was not started from a configuration file."))]
+    MissingConfigFile,
+    #[snafu(display("{}", source))]
+    LoadConfiguration { source: configuration::Error },
+    #[snafu(display("Invalid authentication settings: {}", source))]
+    InvalidAuthentication { source: auth::Error },
+    #[snafu(display("Too many requests, retry in {} seconds.", retry_after))]
+    RateLimited { retry_after: u64 },
+    #[snafu(display("The storage quota of {} bytes is exceeded.", max_bytes))]
+    QuotaExceeded { max_bytes: u64 },
+    #[snafu(display("The counter value must be a 64-bit signed integer."))]
+    InvalidCounterValue,
+    #[snafu(display("{}", source))]
+    UpdateValue { source: kvstore::Error },
+    #[snafu(display("Invalid expiration, {}.", reason))]
+    InvalidExpiration { reason: String },
+    #[snafu(display("The namespace is full, it holds at most {} bytes.", max_bytes))]
+    NamespaceFull { max_bytes: u64 },
+    #[snafu(display("{}", source))]
+    InvalidNamespace { source: namespace::Error },
+    #[snafu(display("{}", source))]
+    LockFailed { source: lease::Error },
 }
 
 impl reject::Reject for Error {}
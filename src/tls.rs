@@ -0,0 +1,294 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use futures::{
+    future::{self, Either},
+    FutureExt,
+};
+use hyper::{
+    server::conn::Http,
+    service::{service_fn, Service},
+    Body, Request,
+};
+use snafu::{ResultExt, Snafu};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc},
+    time,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+        NoClientAuth, RootCertStore, ServerConfig, Session, TLSError,
+    },
+    TlsAcceptor,
+};
+use warp::{Filter, Reply};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName};
+
+use crate::configuration::General;
+
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub subject: String,
+    // Common names and subject alternative names, in that order
+    pub principals: Vec<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: &[u8]) -> Option<ClientCertificate> {
+        let (_, certificate) = X509Certificate::from_der(der).ok()?;
+        let mut principals: Vec<String> = certificate
+            .subject()
+            .iter_common_name()
+            .filter_map(|common_name| common_name.as_str().ok())
+            .map(String::from)
+            .collect();
+        if let Some((_, san)) = certificate.tbs_certificate.subject_alternative_name() {
+            for name in &san.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => principals.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(ClientCertificate {
+            subject: certificate.subject().to_string(),
+            principals,
+        })
+    }
+}
+
+pub fn server_config(general: &General) -> Result<ServerConfig, Error> {
+    let certificates = pemfile::certs(&mut BufReader::new(
+        File::open(&general.ssl_certificate).context(OpenFile {
+            path: general.ssl_certificate.clone(),
+        })?,
+    ))
+    .map_err(|_| Error::ParseCertificate {
+        path: general.ssl_certificate.clone(),
+    })?;
+
+    let key = {
+        let key = std::fs::read(&general.ssl_certificate_key).context(OpenFile {
+            path: general.ssl_certificate_key.clone(),
+        })?;
+        let mut pkcs8 = pemfile::pkcs8_private_keys(&mut key.as_slice()).unwrap_or_default();
+        let mut rsa = pemfile::rsa_private_keys(&mut key.as_slice()).unwrap_or_default();
+        if !pkcs8.is_empty() {
+            pkcs8.remove(0)
+        } else if !rsa.is_empty() {
+            rsa.remove(0)
+        } else {
+            return Err(Error::ParsePrivateKey {
+                path: general.ssl_certificate_key.clone(),
+            });
+        }
+    };
+
+    let mut config = if general.ssl_client_ca.is_empty() {
+        ServerConfig::new(NoClientAuth::new())
+    } else {
+        let mut client_roots = RootCertStore::empty();
+        // A bundle without any valid certificate would reject every client
+        match client_roots.add_pem_file(&mut BufReader::new(
+            File::open(&general.ssl_client_ca).context(OpenFile {
+                path: general.ssl_client_ca.clone(),
+            })?,
+        )) {
+            Ok((added, _)) if added > 0 => {}
+            _ => {
+                return Err(Error::ParseCertificate {
+                    path: general.ssl_client_ca.clone(),
+                })
+            }
+        }
+        if general.ssl_client_auth_required {
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(client_roots))
+        } else {
+            ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(client_roots))
+        }
+    };
+    config
+        .set_single_cert(certificates, key)
+        .context(InvalidCertificate)?;
+    config.set_protocols(&["h2".into(), "http/1.1".into()]);
+    Ok(config)
+}
+
+#[derive(Clone)]
+pub struct TlsConfig {
+    general: General,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsConfig {
+    pub fn new(general: &General) -> Result<TlsConfig, Error> {
+        Ok(TlsConfig {
+            general: general.clone(),
+            current: Arc::new(RwLock::new(Arc::new(server_config(general)?))),
+        })
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Only new connections pick up the reloaded certificate
+    pub fn reload(&self) -> Result<(), Error> {
+        let config = server_config(&self.general)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        [
+            &self.general.ssl_certificate,
+            &self.general.ssl_certificate_key,
+            &self.general.ssl_client_ca,
+        ]
+        .iter()
+        .filter(|path| !path.is_empty())
+        .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+    }
+
+    pub async fn watch_files(self, interval: Duration) {
+        let mut last_modified = self.last_modified();
+        let mut interval = time::interval(interval);
+        loop {
+            interval.tick().await;
+            let modified = self.last_modified();
+            if modified != last_modified {
+                last_modified = modified;
+                match self.reload() {
+                    Ok(()) => info!("SSL certificate changed on disk, reloaded."),
+                    Err(e) => error!("Unable to reload the SSL certificate: {}", e),
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn watch_hangup(self) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Unable to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match self.reload() {
+                Ok(()) => info!("SSL certificate reloaded on SIGHUP."),
+                Err(e) => error!("Unable to reload the SSL certificate: {}", e),
+            }
+        }
+    }
+}
+
+pub async fn serve<F>(
+    filter: F,
+    addr: SocketAddr,
+    config: TlsConfig,
+    shutdown_tx: broadcast::Sender<()>,
+) -> io::Result<()>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let service = warp::service(filter);
+    let mut listener = TcpListener::from_std(std::net::TcpListener::bind(addr)?)?;
+    let mut shutdown_rx = shutdown_tx.subscribe();
+    // Every connection holds a sender, so the receiver closes once all of them are drained
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+    loop {
+        let accepted =
+            match future::select(listener.accept().boxed(), shutdown_rx.recv().boxed()).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => break,
+            };
+        let (stream, remote_addr) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Unable to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = TlsAcceptor::from(config.current());
+        let service = service.clone();
+        let drain_tx = drain_tx.clone();
+        let mut connection_shutdown_rx = shutdown_tx.subscribe();
+        tokio::spawn(async move {
+            let _drain_tx = drain_tx;
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                    return;
+                }
+            };
+            let connection = ConnectionInfo {
+                remote_addr,
+                client_certificate: stream.get_ref().1.get_peer_certificates().and_then(
+                    |certificates| {
+                        certificates
+                            .first()
+                            .and_then(|certificate| ClientCertificate::from_der(&certificate.0))
+                    },
+                ),
+            };
+            let connection_service = service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(connection.clone());
+                service.clone().call(request)
+            });
+            let mut connection = Http::new().serve_connection(stream, connection_service);
+            let result = match future::select(
+                &mut connection,
+                connection_shutdown_rx.recv().boxed(),
+            )
+            .await
+            {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => {
+                    Pin::new(&mut connection).graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Connection with {} closed: {}", remote_addr, e);
+            }
+        });
+    }
+    drop(drain_tx);
+    drain_rx.recv().await;
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to open {}: {}", path, source))]
+    OpenFile { path: String, source: io::Error },
+    #[snafu(display("Unable to parse the certificates in {}", path))]
+    ParseCertificate { path: String },
+    #[snafu(display("Unable to find a PKCS#8 or RSA private key in {}", path))]
+    ParsePrivateKey { path: String },
+    #[snafu(display("Invalid SSL certificate or private key: {}", source))]
+    InvalidCertificate { source: TLSError },
+}
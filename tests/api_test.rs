@@ -1,14 +1,19 @@
+use std::io::Read;
 use std::sync::{Arc, RwLock};
 
+use flate2::read::GzDecoder;
 use hyper::StatusCode;
 use serde_json::Value;
 use tokio::sync::broadcast;
 use warp::{Filter, Reply};
 
 use lucid::{
-    configuration::{Configuration, ServerSentEvent},
-    kvstore::KvStore,
-    server::routes_filter,
+    configuration::{
+        Configuration, Health, History, Http, Limit, Metrics, Quotas, RateLimit, ServerSentEvent,
+        Store,
+    },
+    kvstore::{KvStore, CHUNK_SIZE},
+    server::{redirect_filter, routes_filter, SseMessage},
 };
 
 fn create_routes_filter() -> impl Filter<Extract = (impl Reply,)> + Clone + Send + Sync + 'static {
@@ -139,4 +144,1686 @@ mod tests {
 
         // TODO: parse body and check if the events are correct
     }
+
+    #[tokio::test]
+    async fn sse_update_previews() {
+        let event_tx = Arc::new(broadcast::channel(512).0);
+        let mut event_rx = event_tx.subscribe();
+        let config = Arc::new(RwLock::new(Configuration {
+            sse: ServerSentEvent { enabled: true },
+            ..Default::default()
+        }));
+        let routes = routes_filter(Arc::new(KvStore::new(None)), event_tx, config);
+        let put = |body: String| {
+            warp::test::request()
+                .method("PUT")
+                .path("/api/kv/text")
+                .body(body)
+        };
+
+        put("first".to_string()).reply(&routes).await;
+        put("second".to_string()).reply(&routes).await;
+        put(format!("a{}", "é".repeat(5000))).reply(&routes).await;
+
+        let mut values = Vec::new();
+        while let Ok(SseMessage::Update { value, .. }) = event_rx.try_recv() {
+            values.push(value);
+        }
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0], "second");
+        // Large values are cut before the character crossing the limit
+        assert_eq!(values[1], format!("a{}", "é".repeat(2047)));
+    }
+
+    #[tokio::test]
+    async fn sse_shutdown() {
+        let event_tx = Arc::new(broadcast::channel(512).0);
+        let config = Arc::new(RwLock::new(Configuration {
+            sse: ServerSentEvent { enabled: true },
+            ..Default::default()
+        }));
+        let routes = routes_filter(Arc::new(KvStore::new(None)), event_tx.clone(), config);
+        let sse_reply = warp::test::request()
+            .path("/notifications")
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        event_tx.send(SseMessage::Shutdown).ok();
+
+        // The stream must end after the shutdown event instead of waiting for more
+        let body = hyper::body::to_bytes(sse_reply.into_response().into_body())
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("event:shutdown"));
+    }
+
+    #[tokio::test]
+    async fn reload_config() {
+        let path = std::env::temp_dir().join(format!("lucid-reload-{}.yml", std::process::id()));
+        let running = Configuration {
+            sse: ServerSentEvent { enabled: true },
+            source: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut on_disk = running.clone();
+        on_disk.general.port = 7030;
+        on_disk.sse.enabled = false;
+        std::fs::write(&path, serde_yaml::to_string(&on_disk).unwrap()).unwrap();
+        let config = Arc::new(RwLock::new(running));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config.clone(),
+        );
+        let reply = warp::test::request()
+            .method("POST")
+            .path("/api/admin/config/reload")
+            .filter(&routes)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["reloaded"], serde_json::json!(["sse.enabled"]));
+        assert_eq!(
+            report["restart_required"],
+            serde_json::json!(["general.port"])
+        );
+
+        // Reloadable settings are applied, the others keep their running value
+        let config = config.read().unwrap();
+        assert!(!config.sse.enabled);
+        assert_eq!(config.general.port, 7020);
+    }
+
+    #[tokio::test]
+    async fn prometheus_metrics() {
+        let config = Arc::new(RwLock::new(Configuration {
+            metrics: Metrics {
+                enabled: true,
+                require_auth: false,
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        for (key, mime_type, value) in &[
+            ("foo", "text/plain", "bar"),
+            ("params", "Text/Plain; x=random", "ab"),
+            ("custom", "application/x-random", "c"),
+        ] {
+            warp::test::request()
+                .method("PUT")
+                .path(&format!("/api/kv/{}", key))
+                .header("Content-Type", *mime_type)
+                .body(*value)
+                .reply(&routes)
+                .await;
+        }
+        let response = warp::test::request().path("/metrics").reply(&routes).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(body.contains("lucid_keys 3"));
+        assert!(body.contains("lucid_stored_bytes_by_mime_type{mime_type=\"text/plain\"} 5"));
+        assert!(body.contains("lucid_stored_bytes_by_mime_type{mime_type=\"other\"} 1"));
+        assert!(!body.contains("random"));
+        assert!(body.contains(
+            "lucid_http_requests_total{method=\"PUT\",route=\"/api/kv/{key}\",status=\"201\"}"
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_info() {
+        let routes = create_routes_filter();
+        warp::test::request()
+            .method("PUT")
+            .path("/api/kv/foo")
+            .body(b"bar")
+            .reply(&routes)
+            .await;
+        let response = warp::test::request()
+            .path("/api/admin/info")
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let info: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(info["pid"], std::process::id());
+        assert_eq!(info["store"]["keys"], 1);
+        assert_eq!(info["store"]["stored_bytes"], 3);
+        assert_eq!(
+            info["configuration"]["encryption"]["private_key"],
+            "<redacted>"
+        );
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let config = Arc::new(RwLock::new(Configuration {
+            health: Health {
+                memory_threshold: 1,
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config.clone(),
+        );
+        let live = warp::test::request()
+            .path("/health/live")
+            .reply(&routes)
+            .await;
+        assert_eq!(live.status(), StatusCode::OK);
+
+        warp::test::request()
+            .method("PUT")
+            .path("/api/kv/foo")
+            .body(b"bar")
+            .reply(&routes)
+            .await;
+        let response = warp::test::request()
+            .path("/health/ready")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["checks"]["memory"]["status"], "fail");
+        assert_eq!(body["checks"]["persistence"]["status"], "pass");
+
+        config.write().unwrap().health.memory_threshold = 0;
+        let response = warp::test::request()
+            .path("/health/ready")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rate_limited() {
+        let mut rate_limit = RateLimit {
+            enabled: true,
+            ..Default::default()
+        };
+        rate_limit.write = Limit {
+            rate: 0.01,
+            burst: 1.0,
+        };
+        let config = Arc::new(RwLock::new(Configuration {
+            rate_limit,
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let put = || {
+            warp::test::request()
+                .method("PUT")
+                .path("/api/kv/foo")
+                .body(b"bar")
+        };
+
+        assert_eq!(put().reply(&routes).await.status(), StatusCode::CREATED);
+        let response = put().reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "100");
+
+        // Reads have their own bucket
+        let get = warp::test::request()
+            .path("/api/kv/foo")
+            .reply(&routes)
+            .await;
+        assert_eq!(get.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_charges_once_per_request() {
+        let mut rate_limit = RateLimit {
+            enabled: true,
+            ..Default::default()
+        };
+        rate_limit.read = Limit {
+            rate: 0.01,
+            burst: 2.0,
+        };
+        let config = Arc::new(RwLock::new(Configuration {
+            rate_limit,
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let get = |path: &str| warp::test::request().path(path);
+
+        // Routes without authentication are not charged
+        for _ in 0..3 {
+            assert_eq!(get("/health").reply(&routes).await.status(), StatusCode::OK);
+        }
+        // Routes matched late in the filter tree are charged once
+        assert_eq!(
+            get("/api/locks/a").reply(&routes).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get("/api/kv/x").reply(&routes).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get("/api/kv/x").reply(&routes).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn storage_quota() {
+        let config = Arc::new(RwLock::new(Configuration {
+            quotas: Quotas {
+                max_bytes: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let put = |key: &str, value: &'static [u8]| {
+            warp::test::request()
+                .method("PUT")
+                .path(&format!("/api/kv/{}", key))
+                .body(value)
+        };
+
+        assert_eq!(
+            put("foo", b"bar").reply(&routes).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            put("baz", b"bar").reply(&routes).await.status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        // Replacing a value only counts the difference
+        assert_eq!(
+            put("foo", b"barz").reply(&routes).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn namespaces() {
+        let routes = create_routes_filter();
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Content-Type", "application/json")
+        };
+
+        assert_eq!(
+            request("PUT", "/api/ns/team-a/kv/foo")
+                .body("bar")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request("PUT", "/api/admin/namespaces/team-a")
+                .body("{}")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            request("PUT", "/api/admin/namespaces/team-a")
+                .body("{}")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            request("PUT", "/api/admin/namespaces/team.b")
+                .body("{}")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        assert_eq!(
+            request("PUT", "/api/ns/team-a/kv/foo")
+                .body("bar")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CREATED
+        );
+        let res = request("GET", "/api/ns/team-a/kv/foo").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "bar");
+        // Namespaces do not share their keys with the default one
+        assert_eq!(
+            request("GET", "/api/kv/foo").reply(&routes).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let res = request("GET", "/api/admin/namespaces").reply(&routes).await;
+        let namespaces: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(namespaces[0]["name"], "team-a");
+        assert_eq!(namespaces[0]["keys"], 1);
+        assert_eq!(namespaces[0]["stored_bytes"], 3);
+
+        assert_eq!(
+            request("DELETE", "/api/admin/namespaces/team-a")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            request("GET", "/api/ns/team-a/kv/foo")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn hierarchical_keys() {
+        let routes = create_routes_filter();
+        let request = |method: &str, path: &str| warp::test::request().method(method).path(path);
+
+        for key in &[
+            "users/42/profile",
+            "users/42/avatar",
+            "users/7/profile",
+            "config",
+        ] {
+            assert_eq!(
+                request("PUT", &format!("/api/kv/{}", key))
+                    .body("bar")
+                    .reply(&routes)
+                    .await
+                    .status(),
+                StatusCode::CREATED
+            );
+        }
+        let res = request("GET", "/api/kv/users/42/profile")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "bar");
+
+        let res = request("GET", "/api/kv?prefix=users/42/")
+            .reply(&routes)
+            .await;
+        let list: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            list["keys"],
+            serde_json::json!(["users/42/avatar", "users/42/profile"])
+        );
+        let res = request("GET", "/api/kv").reply(&routes).await;
+        let list: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(list["keys"].as_array().unwrap().len(), 4);
+
+        assert_eq!(
+            request("DELETE", "/api/kv").reply(&routes).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        let res = request("DELETE", "/api/kv?prefix=users/")
+            .reply(&routes)
+            .await;
+        let list: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(list["keys"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            request("GET", "/api/kv/users/7/profile")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request("GET", "/api/kv/config")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn expiration_on_put() {
+        let routes = create_routes_filter();
+        let put = |path: &str| warp::test::request().method("PUT").path(path).body("bar");
+        let get = |path: &str| warp::test::request().path(path);
+
+        assert_eq!(
+            put("/api/kv/foo?ttl=1").reply(&routes).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            put("/api/kv/bar")
+                .header("X-Lucid-TTL", "1")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            put("/api/kv/baz?expire_at=2100-01-01T00:00:00Z")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CREATED
+        );
+        for path in &[
+            "/api/kv/foo?ttl=0",
+            "/api/kv/foo?ttl=soon",
+            "/api/kv/foo?expire_at=2000-01-01T00:00:00Z",
+            "/api/kv/foo?ttl=1&expire_at=2100-01-01T00:00:00Z",
+        ] {
+            assert_eq!(
+                put(path).reply(&routes).await.status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert_eq!(
+            get("/api/kv/foo").reply(&routes).await.status(),
+            StatusCode::OK
+        );
+
+        tokio::time::delay_for(std::time::Duration::from_millis(1100)).await;
+        for path in &["/api/kv/foo", "/api/kv/bar"] {
+            assert_eq!(
+                get(path).reply(&routes).await.status(),
+                StatusCode::NOT_FOUND
+            );
+        }
+        assert_eq!(
+            get("/api/kv/baz").reply(&routes).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn counters() {
+        let routes = create_routes_filter();
+        let patch = |body: &str| {
+            warp::test::request()
+                .method("PATCH")
+                .path("/api/kv/visits")
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        assert_eq!(
+            patch(r#"{"operation": "increment"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        let res = patch(r#"{"operation": "increment", "initial": "100"}"#)
+            .reply(&routes)
+            .await;
+        let counter: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(counter["value"], 100);
+        let res = patch(r#"{"operation": "increment", "value": "25"}"#)
+            .reply(&routes)
+            .await;
+        let counter: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(counter["value"], 125);
+        let res = patch(r#"{"operation": "decrement", "value": "200"}"#)
+            .reply(&routes)
+            .await;
+        let counter: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(counter["value"], -75);
+
+        assert_eq!(
+            patch(r#"{"operation": "increment", "value": "1.5"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            patch(r#"{"operation": "increment", "value": "9223372036854775807"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            patch(r#"{"operation": "increment", "value": "9223372036854775807"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn lists() {
+        let routes = create_routes_filter();
+        let patch = |body: &'static str| {
+            warp::test::request()
+                .method("PATCH")
+                .path("/api/kv/jobs")
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        let res = patch(r#"{"operation": "rpush", "values": ["a", "b", "c"]}"#)
+            .reply(&routes)
+            .await;
+        let list: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(list["length"], 3);
+        let res = warp::test::request()
+            .path("/api/kv/jobs?start=1&stop=-1")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), r#"["b","c"]"#);
+
+        let res = patch(r#"{"operation": "lpop"}"#).reply(&routes).await;
+        let list: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(list["value"], "a");
+        let res = patch(r#"{"operation": "ltrim", "start": 0, "stop": 0}"#)
+            .reply(&routes)
+            .await;
+        let list: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(list["length"], 1);
+        assert_eq!(
+            patch(r#"{"operation": "increment"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CONFLICT
+        );
+        patch(r#"{"operation": "rpop"}"#).reply(&routes).await;
+
+        // A blocking pop waits until a value is pushed
+        let blocking_routes = routes.clone();
+        let blocking_pop = tokio::spawn(async move {
+            patch(r#"{"operation": "blpop", "timeout": 5}"#)
+                .reply(&blocking_routes)
+                .await
+        });
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+        patch(r#"{"operation": "rpush", "value": "d"}"#)
+            .reply(&routes)
+            .await;
+        let res = blocking_pop.await.unwrap();
+        let list: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(list["value"], "d");
+
+        assert_eq!(
+            patch(r#"{"operation": "brpop", "timeout": 1}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_pop_timeout_is_capped() {
+        let config = Arc::new(RwLock::new(Configuration {
+            store: Store {
+                max_pop_timeout: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let started_at = std::time::Instant::now();
+        let res = warp::test::request()
+            .method("PATCH")
+            .path("/api/kv/jobs")
+            .header("Content-Type", "application/json")
+            .body(format!(
+                r#"{{"operation": "blpop", "timeout": {}}}"#,
+                u64::MAX
+            ))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(started_at.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn hashes() {
+        let store = Arc::new(KvStore::new(None));
+        let event_tx = Arc::new(broadcast::channel(512).0);
+        let mut event_rx = event_tx.subscribe();
+        let routes = routes_filter(store, event_tx, Arc::new(RwLock::new(Default::default())));
+        let patch = |body: &'static str| {
+            warp::test::request()
+                .method("PATCH")
+                .path("/api/kv/users/42")
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        let res = patch(r#"{"operation": "hset", "fields": {"name": "Alice", "city": "Paris"}}"#)
+            .reply(&routes)
+            .await;
+        let hash: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(hash["count"], 2);
+        let res = warp::test::request()
+            .path("/api/kv/users/42?field=city")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), "Paris");
+
+        let res = patch(r#"{"operation": "hincrby", "field": "logins", "value": "3"}"#)
+            .reply(&routes)
+            .await;
+        let hash: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(hash["value"], 3);
+        patch(r#"{"operation": "hdel", "field": "city"}"#)
+            .reply(&routes)
+            .await;
+
+        let res = warp::test::request()
+            .path("/api/kv/users/42")
+            .reply(&routes)
+            .await;
+        let hash: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(hash, serde_json::json!({"logins": "3", "name": "Alice"}));
+        assert_eq!(
+            warp::test::request()
+                .path("/api/kv/users/42?field=city")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // Each changed field is notified
+        let mut events = Vec::new();
+        while let Ok(SseMessage::FieldUpdate { key, field, value }) = event_rx.try_recv() {
+            events.push((key, field, value));
+        }
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[3],
+            ("users/42".to_string(), "city".to_string(), None)
+        );
+    }
+
+    #[tokio::test]
+    async fn sets() {
+        let routes = create_routes_filter();
+        let patch = |key: &str, body: &'static str| {
+            warp::test::request()
+                .method("PATCH")
+                .path(&format!("/api/kv/{}", key))
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+        let get = |path: &str| warp::test::request().path(path);
+
+        patch(
+            "tags/a",
+            r#"{"operation": "sadd", "values": ["rust", "kv", "http"]}"#,
+        )
+        .reply(&routes)
+        .await;
+        patch(
+            "tags/b",
+            r#"{"operation": "sadd", "values": ["kv", "rust", "sql"]}"#,
+        )
+        .reply(&routes)
+        .await;
+        assert_eq!(
+            get("/api/kv/tags/a?member=http")
+                .reply(&routes)
+                .await
+                .body(),
+            "true"
+        );
+        assert_eq!(
+            get("/api/kv/tags/a?intersect=tags/b")
+                .reply(&routes)
+                .await
+                .body(),
+            r#"["kv","rust"]"#
+        );
+
+        patch(
+            "scores",
+            r#"{"operation": "zadd", "scores": {"alice": 3, "bob": 1}}"#,
+        )
+        .reply(&routes)
+        .await;
+        let res = patch(
+            "scores",
+            r#"{"operation": "zincrby", "value": "bob", "score": 5}"#,
+        )
+        .reply(&routes)
+        .await;
+        let message: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(message["score"], 6.0);
+        let res = get("/api/kv/scores?start=-1").reply(&routes).await;
+        let top: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(top, serde_json::json!([{"member": "bob", "score": 6.0}]));
+        let res = get("/api/kv/scores?min=0&max=5").reply(&routes).await;
+        let low: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(low, serde_json::json!([{"member": "alice", "score": 3.0}]));
+
+        assert_eq!(
+            patch("scores", r#"{"operation": "sadd", "value": "carol"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[tokio::test]
+    async fn json_documents() {
+        let routes = create_routes_filter();
+        let get = |path: &str| warp::test::request().path(path);
+        let patch = |content_type: &str, body: &'static str| {
+            warp::test::request()
+                .method("PATCH")
+                .path("/api/kv/doc")
+                .header("Content-Type", content_type)
+                .body(body)
+        };
+
+        warp::test::request()
+            .method("PUT")
+            .path("/api/kv/doc")
+            .header("Content-Type", "application/json")
+            .body(r#"{"user": {"name": "Alice", "tags": ["a", "b"]}}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            get("/api/kv/doc?path=$.user.name")
+                .reply(&routes)
+                .await
+                .body(),
+            r#""Alice""#
+        );
+        assert_eq!(
+            get("/api/kv/doc?path=$.user.tags[1]")
+                .reply(&routes)
+                .await
+                .body(),
+            r#""b""#
+        );
+        assert_eq!(
+            get("/api/kv/doc?path=$.user.age")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let res = patch(
+            "application/json-patch+json",
+            r#"[{"op": "replace", "path": "/user/name", "value": "Bob"},
+                {"op": "add", "path": "/user/tags/-", "value": "c"}]"#,
+        )
+        .reply(&routes)
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // A failing operation leaves the document untouched
+        let res = patch(
+            "application/json-patch+json",
+            r#"[{"op": "remove", "path": "/user/tags"},
+                {"op": "test", "path": "/user/name", "value": "Alice"}]"#,
+        )
+        .reply(&routes)
+        .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = patch(
+            "application/merge-patch+json",
+            r#"{"user": {"tags": null, "age": 42}}"#,
+        )
+        .reply(&routes)
+        .await;
+        let document: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            document,
+            serde_json::json!({"user": {"name": "Bob", "age": 42}})
+        );
+
+        warp::test::request()
+            .method("PUT")
+            .path("/api/kv/text")
+            .body("not json")
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            warp::test::request()
+                .method("PATCH")
+                .path("/api/kv/text")
+                .header("Content-Type", "application/merge-patch+json")
+                .body("{}")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[tokio::test]
+    async fn namespace_eviction() {
+        let routes = create_routes_filter();
+        let request = |method: &str, path: &str| warp::test::request().method(method).path(path);
+
+        let settings = r#"{"max_bytes": 6, "eviction": "oldest"}"#;
+        request("PUT", "/api/admin/namespaces/cache")
+            .body(settings)
+            .reply(&routes)
+            .await;
+        request("PUT", "/api/admin/namespaces/strict")
+            .body(r#"{"max_bytes": 6}"#)
+            .reply(&routes)
+            .await;
+
+        for key in &["foo", "bar", "baz"] {
+            assert_eq!(
+                request("PUT", &format!("/api/ns/cache/kv/{}", key))
+                    .body("abc")
+                    .reply(&routes)
+                    .await
+                    .status(),
+                StatusCode::CREATED
+            );
+        }
+        assert_eq!(
+            request("GET", "/api/ns/cache/kv/foo")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request("GET", "/api/ns/cache/kv/baz")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+
+        request("PUT", "/api/ns/strict/kv/foo")
+            .body("abcdef")
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            request("PUT", "/api/ns/strict/kv/bar")
+                .body("abc")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn collection_limits() {
+        let config = Arc::new(RwLock::new(Configuration {
+            quotas: Quotas {
+                max_bytes: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let request = |method: &str, path: &str| warp::test::request().method(method).path(path);
+        let patch = |path: &str, body: &'static str| {
+            request("PATCH", path)
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+        let rpush = r#"{"operation": "rpush", "value": "abc"}"#;
+
+        // ["abc","abc"] takes 13 bytes, a third value would exceed the quota
+        for _ in 0..2 {
+            assert_eq!(
+                patch("/api/kv/jobs", rpush).reply(&routes).await.status(),
+                StatusCode::OK
+            );
+        }
+        assert_eq!(
+            patch("/api/kv/jobs", rpush).reply(&routes).await.status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        let res = request("GET", "/api/kv/jobs").reply(&routes).await;
+        assert_eq!(res.body(), r#"["abc","abc"]"#);
+        request("DELETE", "/api/kv/jobs").reply(&routes).await;
+
+        request("PUT", "/api/admin/namespaces/strict")
+            .body(r#"{"max_bytes": 10}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            patch("/api/ns/strict/kv/jobs", rpush)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            patch("/api/ns/strict/kv/jobs", rpush)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+
+        request("PUT", "/api/admin/namespaces/small")
+            .body(r#"{"max_limit": 8}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            patch(
+                "/api/ns/small/kv/user",
+                r#"{"operation": "hset", "field": "a", "value": "b"}"#
+            )
+            .reply(&routes)
+            .await
+            .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            request("GET", "/api/ns/small/kv/user")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // Counters are bound by the same limits
+        let increment = |body: &'static str| patch("/api/ns/small/kv/counter", body);
+        assert_eq!(
+            increment(r#"{"operation": "increment", "initial": "123456789"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            increment(r#"{"operation": "increment", "initial": "1234567"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            increment(r#"{"operation": "increment", "value": "100000000"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        let res = request("GET", "/api/ns/small/kv/counter")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), "1234567");
+
+        // So are JSON documents
+        request("DELETE", "/api/ns/small/kv/counter")
+            .reply(&routes)
+            .await;
+        let res = request("PUT", "/api/ns/small/kv/doc")
+            .body(r#"{"a":1}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = request("PATCH", "/api/ns/small/kv/doc")
+            .header("Content-Type", "application/merge-patch+json")
+            .body(r#"{"b":2}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = request("PATCH", "/api/ns/small/kv/doc")
+            .header("Content-Type", "application/json-patch+json")
+            .body(r#"[{"op": "copy", "from": "/a", "path": "/b"}]"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = request("GET", "/api/ns/small/kv/doc").reply(&routes).await;
+        assert_eq!(res.body(), r#"{"a":1}"#);
+    }
+
+    #[tokio::test]
+    async fn collection_length_limit() {
+        let config = Arc::new(RwLock::new(Configuration {
+            store: Store {
+                max_collection_len: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let patch = |body: &'static str| {
+            warp::test::request()
+                .method("PATCH")
+                .path("/api/kv/tags")
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        assert_eq!(
+            patch(r#"{"operation": "sadd", "values": ["a", "b", "c"]}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            patch(r#"{"operation": "sadd", "values": ["a", "b"]}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            patch(r#"{"operation": "sadd", "value": "c"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        // Members already in the set do not grow it
+        assert_eq!(
+            patch(r#"{"operation": "sadd", "value": "a"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        let res = warp::test::request()
+            .path("/api/kv/tags")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), r#"["a","b"]"#);
+    }
+
+    #[tokio::test]
+    async fn append_and_ranges() {
+        let routes = create_routes_filter();
+        let request = |method: &str| warp::test::request().method(method).path("/api/kv/log");
+
+        let res = request("PATCH")
+            .body(r#"{"operation": "append", "value": "0123"}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("PATCH")
+            .body(r#"{"operation": "append", "value": "456789"}"#)
+            .reply(&routes)
+            .await;
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["length"], 10);
+
+        let res = request("GET").reply(&routes).await;
+        assert_eq!(res.body(), "0123456789");
+        assert_eq!(res.headers()["accept-ranges"], "bytes");
+
+        for (range, content_range, expected) in &[
+            ("bytes=2-4", "bytes 2-4/10", "234"),
+            ("bytes=7-", "bytes 7-9/10", "789"),
+            ("bytes=-2", "bytes 8-9/10", "89"),
+            ("bytes=8-100", "bytes 8-9/10", "89"),
+        ] {
+            let res = request("GET").header("range", *range).reply(&routes).await;
+            assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(res.headers()["content-range"], *content_range);
+            assert_eq!(res.body(), expected);
+        }
+
+        let res = request("GET")
+            .header("range", "bytes=10-")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()["content-range"], "bytes */10");
+
+        let res = request("GET")
+            .header("range", "bytes=0-1,4-5")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "0123456789");
+
+        let res = request("PATCH")
+            .body(r#"{"operation": "append"}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn append_limits() {
+        let config = Arc::new(RwLock::new(Configuration {
+            store: Store {
+                max_limit: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let append = |path: &str, value: &str| {
+            warp::test::request()
+                .method("PATCH")
+                .path(path)
+                .body(format!(
+                    r#"{{"operation": "append", "value": "{}"}}"#,
+                    value
+                ))
+        };
+
+        for value in &["ab", "cd"] {
+            assert_eq!(
+                append("/api/kv/log", value).reply(&routes).await.status(),
+                StatusCode::OK
+            );
+        }
+        assert_eq!(
+            append("/api/kv/log", "e").reply(&routes).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        let res = warp::test::request()
+            .path("/api/kv/log")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), "abcd");
+
+        warp::test::request()
+            .method("PUT")
+            .path("/api/admin/namespaces/strict")
+            .body(r#"{"max_limit": 16, "max_bytes": 6}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            append("/api/ns/strict/kv/log", "abcd")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            append("/api/ns/strict/kv/log", "efg")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn large_values() {
+        let routes = create_routes_filter();
+        let value: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/kv/large")
+            .body(value.clone())
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = warp::test::request()
+            .path("/api/kv/large")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-length"], "1000000");
+        assert_eq!(res.body().to_vec(), value);
+
+        let res = warp::test::request()
+            .method("HEAD")
+            .path("/api/kv/large")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.headers()["content-length"], "1000000");
+    }
+
+    #[tokio::test]
+    async fn response_compression() {
+        let config = Arc::new(RwLock::new(Configuration {
+            http: Http {
+                compression: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let value = "compressible ".repeat(100);
+        warp::test::request()
+            .method("PUT")
+            .path("/api/kv/text")
+            .body(value.clone())
+            .reply(&routes)
+            .await;
+
+        let res = warp::test::request()
+            .path("/api/kv/text")
+            .header("accept-encoding", "deflate;q=0.5, gzip")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        assert_eq!(res.headers()["vary"], "accept-encoding");
+        let mut decoded = String::new();
+        GzDecoder::new(&res.body()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, value);
+
+        let res = warp::test::request()
+            .path("/api/kv/text")
+            .header("accept-encoding", "gzip;q=0, br")
+            .reply(&routes)
+            .await;
+        assert!(!res.headers().contains_key("content-encoding"));
+        assert_eq!(res.body(), value.as_bytes());
+
+        let res = warp::test::request()
+            .path("/api/kv/text")
+            .header("accept-encoding", "gzip")
+            .header("range", "bytes=0-11")
+            .reply(&routes)
+            .await;
+        assert!(!res.headers().contains_key("content-encoding"));
+        assert_eq!(res.body(), "compressible");
+
+        // Values larger than a chunk are streamed without being buffered
+        let large = "compressible ".repeat(CHUNK_SIZE / 4);
+        warp::test::request()
+            .method("PUT")
+            .path("/api/kv/large")
+            .body(large.clone())
+            .reply(&routes)
+            .await;
+        let res = warp::test::request()
+            .path("/api/kv/large")
+            .header("accept-encoding", "gzip")
+            .reply(&routes)
+            .await;
+        assert!(!res.headers().contains_key("content-encoding"));
+        assert_eq!(res.body(), large.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn version_history() {
+        let settings = Store {
+            history: History {
+                max_versions: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let store = KvStore::new(None).with_history(settings.history.clone());
+        let config = Arc::new(RwLock::new(Configuration {
+            store: settings,
+            ..Default::default()
+        }));
+        let routes = routes_filter(Arc::new(store), Arc::new(broadcast::channel(512).0), config);
+        let request = |method: &str, path: &str| warp::test::request().method(method).path(path);
+        for value in &["first", "second"] {
+            request("PUT", "/api/kv/doc")
+                .body(*value)
+                .reply(&routes)
+                .await;
+        }
+
+        let res = request("GET", "/api/kv/doc?version=1").reply(&routes).await;
+        assert_eq!(res.body(), "first");
+        assert_eq!(
+            request("GET", "/api/kv/doc?version=9")
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let res = request("GET", "/api/kv/doc/history").reply(&routes).await;
+        let history: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(history[0]["version"], 2);
+        assert_eq!(history[0]["current"], true);
+        assert_eq!(history[1]["version"], 1);
+        assert_eq!(history[1]["size"], 5);
+
+        let res = request("PATCH", "/api/kv/doc")
+            .body(r#"{"operation": "restore", "version": 1}"#)
+            .reply(&routes)
+            .await;
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["version"], 3);
+        let res = request("GET", "/api/kv/doc").reply(&routes).await;
+        assert_eq!(res.body(), "first");
+        assert_eq!(res.headers()["x-lucid-version"], "3");
+
+        // An existing key takes precedence over the history of its parent
+        request("PUT", "/api/kv/doc/history")
+            .body("value")
+            .reply(&routes)
+            .await;
+        let res = request("GET", "/api/kv/doc/history").reply(&routes).await;
+        assert_eq!(res.body(), "value");
+
+        // Updates made with a PATCH keep the previous value too
+        request("PUT", "/api/kv/json")
+            .body(r#"{"a": 1}"#)
+            .reply(&routes)
+            .await;
+        request("PATCH", "/api/kv/json")
+            .header("Content-Type", "application/merge-patch+json")
+            .body(r#"{"b": 2}"#)
+            .reply(&routes)
+            .await;
+        let res = request("GET", "/api/kv/json?version=1")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), r#"{"a": 1}"#);
+        for operation in &[
+            r#"{"operation": "append", "value": "0"}"#,
+            r#"{"operation": "increment"}"#,
+        ] {
+            request("PATCH", "/api/kv/counter")
+                .body(*operation)
+                .reply(&routes)
+                .await;
+        }
+        let res = request("GET", "/api/kv/counter?version=1")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), "0");
+        request("PATCH", "/api/kv/list")
+            .body(r#"{"operation": "rpush", "values": ["a", "b"]}"#)
+            .reply(&routes)
+            .await;
+        request("PATCH", "/api/kv/list")
+            .body(r#"{"operation": "lpop"}"#)
+            .reply(&routes)
+            .await;
+        let res = request("GET", "/api/kv/list?version=1")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.body(), r#"["a","b"]"#);
+    }
+
+    #[tokio::test]
+    async fn trash() {
+        let config = Arc::new(RwLock::new(Configuration {
+            store: Store {
+                trash_retention: 60,
+                ..Default::default()
+            },
+            quotas: Quotas {
+                max_bytes: 8,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let routes = routes_filter(
+            Arc::new(KvStore::new(None)),
+            Arc::new(broadcast::channel(512).0),
+            config,
+        );
+        let request = |method: &str, path: &str| warp::test::request().method(method).path(path);
+        let status = |res: warp::http::Response<_>| res.status();
+        let restore = || request("PATCH", "/api/trash/doc").body(r#"{"operation": "restore"}"#);
+
+        request("PUT", "/api/kv/doc")
+            .body("value")
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            status(request("DELETE", "/api/kv/doc").reply(&routes).await),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status(request("GET", "/api/kv/doc").reply(&routes).await),
+            StatusCode::NOT_FOUND
+        );
+
+        let res = request("GET", "/api/trash").reply(&routes).await;
+        let trashed: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(trashed[0]["key"], "doc");
+        assert_eq!(trashed[0]["size"], 5);
+
+        assert_eq!(status(restore().reply(&routes).await), StatusCode::OK);
+        let res = request("GET", "/api/kv/doc").reply(&routes).await;
+        assert_eq!(res.body(), "value");
+        assert_eq!(
+            status(restore().reply(&routes).await),
+            StatusCode::NOT_FOUND
+        );
+
+        request("DELETE", "/api/kv/doc").reply(&routes).await;
+        request("PUT", "/api/kv/doc")
+            .body("other")
+            .reply(&routes)
+            .await;
+        assert_eq!(status(restore().reply(&routes).await), StatusCode::CONFLICT);
+        assert_eq!(
+            status(request("DELETE", "/api/trash/doc").reply(&routes).await),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status(request("DELETE", "/api/trash/doc").reply(&routes).await),
+            StatusCode::NOT_FOUND
+        );
+
+        // Trashed values leave the quota, restoring one has to fit in it again
+        request("DELETE", "/api/kv/doc").reply(&routes).await;
+        request("PUT", "/api/kv/other")
+            .body("abcd")
+            .reply(&routes)
+            .await;
+        assert_eq!(
+            status(restore().reply(&routes).await),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        request("DELETE", "/api/kv/other").reply(&routes).await;
+        assert_eq!(status(restore().reply(&routes).await), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn locks() {
+        let routes = create_routes_filter();
+        let request = |method: &str, path: &str| warp::test::request().method(method).path(path);
+        let json = |res: warp::http::Response<hyper::body::Bytes>| -> (StatusCode, Value) {
+            let body = serde_json::from_slice(res.body()).unwrap();
+            (res.status(), body)
+        };
+
+        let (status, lease) = json(request("POST", "/api/locks/jobs").reply(&routes).await);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(lease["fencing_token"], 1);
+        let token = lease["token"].as_str().unwrap().to_string();
+
+        let (status, _) = json(request("POST", "/api/locks/jobs").reply(&routes).await);
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, held) = json(request("GET", "/api/locks/jobs").reply(&routes).await);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(held["fencing_token"], 1);
+        assert!(held.get("token").is_none());
+
+        let (status, _) = json(
+            request("DELETE", "/api/locks/jobs")
+                .header("x-lucid-lock-token", "other")
+                .reply(&routes)
+                .await,
+        );
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, renewed) = json(
+            request("PATCH", "/api/locks/jobs?ttl=60")
+                .header("x-lucid-lock-token", &token)
+                .reply(&routes)
+                .await,
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(renewed["token"], token.as_str());
+        let (status, _) = json(
+            request("POST", "/api/locks/jobs?ttl=100000")
+                .reply(&routes)
+                .await,
+        );
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let waiter = request("POST", "/api/locks/jobs?wait=5").reply(&routes);
+        let release = async {
+            tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            request("DELETE", "/api/locks/jobs")
+                .header("x-lucid-lock-token", &token)
+                .reply(&routes)
+                .await
+        };
+        let (waiter, release) = futures::join!(waiter, release);
+        assert_eq!(release.status(), StatusCode::OK);
+        let (status, lease) = json(waiter);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(lease["fencing_token"], 2);
+        assert_ne!(lease["token"], token.as_str());
+
+        let (status, _) = json(request("GET", "/api/locks/other").reply(&routes).await);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Writes fenced by the first lease are refused once the lock changed hands
+        let write = |fencing_token: u64| {
+            request("PUT", "/api/kv/jobs")
+                .header("x-lucid-fencing-token", fencing_token)
+                .body("value")
+        };
+        assert_eq!(write(2).reply(&routes).await.status(), StatusCode::CREATED);
+        assert_eq!(write(1).reply(&routes).await.status(), StatusCode::CONFLICT);
+        let res = request("PATCH", "/api/kv/jobs")
+            .header("x-lucid-fencing-token", "1")
+            .body(r#"{"operation": "append", "value": "!"}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn key_locks_are_leases() {
+        let routes = create_routes_filter();
+        let request = |method: &str, path: &str| warp::test::request().method(method).path(path);
+        let patch = |body: &str| request("PATCH", "/api/kv/doc").body(body);
+        let put = || request("PUT", "/api/kv/doc").body("value");
+        put().reply(&routes).await;
+
+        let res = patch(r#"{"operation": "lock"}"#).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let lease: Value = serde_json::from_slice(res.body()).unwrap();
+        let token = lease["token"].as_str().unwrap();
+        let fencing_token = lease["fencing_token"].to_string();
+        assert_eq!(
+            patch(r#"{"operation": "lock"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(put().reply(&routes).await.status(), StatusCode::FORBIDDEN);
+        // The holder of the lock cannot update the key either
+        assert_eq!(
+            put()
+                .header("x-lucid-fencing-token", &fencing_token)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::FORBIDDEN
+        );
+
+        assert_eq!(
+            patch(r#"{"operation": "unlock"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            patch(r#"{"operation": "unlock", "token": "other"}"#)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::FORBIDDEN
+        );
+        let unlock = format!(r#"{{"operation": "unlock", "token": "{}"}}"#, token);
+        assert_eq!(patch(&unlock).reply(&routes).await.status(), StatusCode::OK);
+        assert_eq!(put().reply(&routes).await.status(), StatusCode::OK);
+
+        // Deletes are fenced like other writes
+        let delete = || request("DELETE", "/api/kv/doc");
+        assert_eq!(
+            delete()
+                .header("x-lucid-fencing-token", &fencing_token)
+                .reply(&routes)
+                .await
+                .status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            request("GET", "/api/kv/doc").reply(&routes).await.body(),
+            "value"
+        );
+        assert_eq!(
+            delete().reply(&routes).await.status(),
+            StatusCode::NO_CONTENT
+        );
+    }
+
+    #[tokio::test]
+    async fn redirect_to_ssl() {
+        let redirect = redirect_filter(7021);
+        let reply = warp::test::request()
+            .path("/api/kv/foo?raw=true")
+            .header("Host", "lucid.example.com:7020")
+            .filter(&redirect)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()["location"],
+            "https://lucid.example.com:7021/api/kv/foo?raw=true"
+        );
+    }
 }
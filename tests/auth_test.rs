@@ -0,0 +1,270 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::{Duration, Utc};
+use hyper::StatusCode;
+use jsonwebtoken::{Algorithm, Header};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use tokio::sync::broadcast;
+use warp::{Filter, Reply};
+
+use lucid::{
+    configuration::{ApiKey, Authentication, CertificateMapping, Claims, Configuration, Scope},
+    kvstore::KvStore,
+    server::routes_filter,
+    tls::{ClientCertificate, ConnectionInfo},
+};
+
+const ISSUER: &str = "https://idp.example.com/";
+const API_KEY: &str = "lucid-backup-cron-key";
+
+struct IdentityProvider {
+    private_key: Vec<u8>,
+    jwks_path: String,
+}
+
+impl IdentityProvider {
+    fn new(name: &str) -> IdentityProvider {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let point = key_pair.public_key().as_ref();
+        let jwks = serde_json::json!({
+            "keys": [{
+                "kty": "EC",
+                "kid": "idp-1",
+                "use": "sig",
+                "crv": "P-256",
+                "x": base64::encode_config(&point[1..33], base64::URL_SAFE_NO_PAD),
+                "y": base64::encode_config(&point[33..], base64::URL_SAFE_NO_PAD),
+            }]
+        });
+        let jwks_path =
+            std::env::temp_dir().join(format!("lucid-{}-{}.jwks", name, std::process::id()));
+        std::fs::write(&jwks_path, jwks.to_string()).unwrap();
+
+        IdentityProvider {
+            private_key: pem::encode(&pem::Pem {
+                tag: String::from("PRIVATE KEY"),
+                contents: pkcs8.as_ref().to_vec(),
+            })
+            .into_bytes(),
+            jwks_path: jwks_path.to_string_lossy().to_string(),
+        }
+    }
+
+    fn issue(&self, issuer: &str) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(String::from("idp-1"));
+        jsonwebtoken::encode(
+            &header,
+            &Claims {
+                sub: String::from("worker"),
+                iss: issuer.to_string(),
+                iat: Utc::now().timestamp(),
+                exp: (Utc::now() + Duration::hours(1)).timestamp(),
+                scope: None,
+            },
+            &self.private_key,
+        )
+        .unwrap()
+    }
+}
+
+fn create_routes_filter(
+    authentication: Authentication,
+) -> impl Filter<Extract = (impl Reply,)> + Clone + Send + Sync + 'static {
+    let store = Arc::new(KvStore::new(None));
+    let event_tx = Arc::new(broadcast::channel(512).0);
+    let config = Arc::new(RwLock::new(Configuration {
+        authentication,
+        ..Default::default()
+    }));
+    routes_filter(store, event_tx, config)
+}
+
+fn jwks_authentication(idp: &IdentityProvider) -> Authentication {
+    Authentication {
+        enabled: true,
+        algorithms: vec![Algorithm::ES256],
+        jwks: idp.jwks_path.clone(),
+        issuer: ISSUER.to_string(),
+        ..Default::default()
+    }
+}
+
+fn certificate_authentication() -> Authentication {
+    Authentication {
+        enabled: true,
+        certificates: vec![CertificateMapping {
+            principal: String::from("spiffe://acme/billing"),
+            name: String::from("billing"),
+            scopes: vec![Scope::Read],
+        }],
+        ..Default::default()
+    }
+}
+
+fn client_connection(principals: &[&str]) -> ConnectionInfo {
+    ConnectionInfo {
+        remote_addr: ([127, 0, 0, 1], 40000).into(),
+        client_certificate: Some(ClientCertificate {
+            subject: String::from("CN=billing"),
+            principals: principals.iter().map(|p| p.to_string()).collect(),
+        }),
+    }
+}
+
+fn api_key_authentication() -> Authentication {
+    Authentication {
+        enabled: true,
+        api_keys: vec![ApiKey {
+            name: String::from("backup-cron"),
+            hash: hex::encode(digest::digest(&digest::SHA256, API_KEY.as_bytes())),
+            scopes: vec![Scope::Read],
+        }],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn jwks_token_accepted() {
+        let idp = IdentityProvider::new("jwks-accepted");
+        let routes = create_routes_filter(jwks_authentication(&idp));
+        let reply = warp::test::request()
+            .path("/api/kv/foo")
+            .header("Authorization", format!("Bearer {}", idp.issue(ISSUER)))
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn jwks_token_with_wrong_issuer_rejected() {
+        let idp = IdentityProvider::new("jwks-issuer");
+        let routes = create_routes_filter(jwks_authentication(&idp));
+        let reply = warp::test::request()
+            .path("/api/kv/foo")
+            .header(
+                "Authorization",
+                format!("Bearer {}", idp.issue("https://evil.example.com/")),
+            )
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn hmac_token_rejected_when_not_allowed() {
+        let idp = IdentityProvider::new("jwks-hmac");
+        let routes = create_routes_filter(jwks_authentication(&idp));
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &Claims {
+                sub: String::from("worker"),
+                iss: ISSUER.to_string(),
+                iat: Utc::now().timestamp(),
+                exp: (Utc::now() + Duration::hours(1)).timestamp(),
+                scope: None,
+            },
+            b"secret",
+        )
+        .unwrap();
+        let reply = warp::test::request()
+            .path("/api/kv/foo")
+            .header("Authorization", format!("Bearer {}", token))
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn api_key_header_accepted() {
+        let routes = create_routes_filter(api_key_authentication());
+        let reply = warp::test::request()
+            .path("/api/kv/foo")
+            .header("X-Api-Key", API_KEY)
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn api_key_without_write_scope_forbidden() {
+        let routes = create_routes_filter(api_key_authentication());
+        let reply = warp::test::request()
+            .method("PUT")
+            .path("/api/kv/foo")
+            .header("Authorization", format!("ApiKey {}", API_KEY))
+            .body(b"bar")
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn unknown_api_key_rejected() {
+        let routes = create_routes_filter(api_key_authentication());
+        let reply = warp::test::request()
+            .path("/api/kv/foo")
+            .header("Authorization", "ApiKey not-a-key")
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn mapped_client_certificate_accepted() {
+        let routes = create_routes_filter(certificate_authentication());
+        let reply = warp::test::request()
+            .path("/api/kv/foo")
+            .extension(client_connection(&["billing", "spiffe://acme/billing"]))
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unmapped_client_certificate_rejected() {
+        let routes = create_routes_filter(certificate_authentication());
+        let reply = warp::test::request()
+            .path("/api/kv/foo")
+            .extension(client_connection(&["intruder"]))
+            .filter(&routes)
+            .await
+            .unwrap();
+
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
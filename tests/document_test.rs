@@ -0,0 +1,90 @@
+use serde_json::json;
+
+use lucid::document::{self, apply_patch, merge_patch, parse_patch, select};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_patch_operations() {
+        let mut doc = json!({"a/b": 1, "list": [1, 2, 3], "nested": {"x": true}});
+        let operations = parse_patch(
+            br#"[
+                {"op": "move", "from": "/a~1b", "path": "/moved"},
+                {"op": "copy", "from": "/nested", "path": "/list/0"},
+                {"op": "remove", "path": "/list/3"},
+                {"op": "test", "path": "/moved", "value": 1}
+            ]"#,
+        )
+        .unwrap();
+
+        apply_patch(&mut doc, &operations).unwrap();
+        assert_eq!(
+            doc,
+            json!({"moved": 1, "list": [{"x": true}, 1, 2], "nested": {"x": true}})
+        );
+    }
+
+    #[test]
+    fn json_patch_is_atomic() {
+        let mut doc = json!({"a": 1});
+        let operations = parse_patch(
+            br#"[{"op": "add", "path": "/b", "value": 2}, {"op": "remove", "path": "/c"}]"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            apply_patch(&mut doc, &operations),
+            Err(document::Error::PathNotFound { .. })
+        ));
+        assert_eq!(doc, json!({"a": 1}));
+        assert!(parse_patch(br#"[{"op": "unknown", "path": "/a"}]"#).is_err());
+    }
+
+    #[test]
+    fn merge_patch_example() {
+        // From RFC 7396
+        let mut doc = json!({
+            "title": "Goodbye!",
+            "author": {"givenName": "John", "familyName": "Doe"},
+            "tags": ["example", "sample"],
+            "content": "This will be unchanged"
+        });
+        merge_patch(
+            &mut doc,
+            &json!({
+                "title": "Hello!",
+                "phoneNumber": "+01-123-456-7890",
+                "author": {"familyName": null},
+                "tags": ["example"]
+            }),
+        );
+        assert_eq!(
+            doc,
+            json!({
+                "title": "Hello!",
+                "author": {"givenName": "John"},
+                "tags": ["example"],
+                "content": "This will be unchanged",
+                "phoneNumber": "+01-123-456-7890"
+            })
+        );
+    }
+
+    #[test]
+    fn select_paths() {
+        let doc = json!({"user": {"name": "Alice", "first name": "A", "tags": ["a", "b"]}});
+
+        assert_eq!(select(&doc, "$").unwrap(), Some(&doc));
+        assert_eq!(select(&doc, "$.user.name").unwrap(), Some(&json!("Alice")));
+        assert_eq!(
+            select(&doc, "$.user['first name']").unwrap(),
+            Some(&json!("A"))
+        );
+        assert_eq!(select(&doc, "$.user.tags[1]").unwrap(), Some(&json!("b")));
+        assert_eq!(select(&doc, "/user/tags/0").unwrap(), Some(&json!("a")));
+        assert_eq!(select(&doc, "$.user.age").unwrap(), None);
+        assert!(select(&doc, "user.name").is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use lucid::kvstore::KvStore;
+use std::collections::BTreeMap;
+
+use chrono::{Duration, Utc};
+use lucid::configuration::{Compression, CompressionAlgorithm, History};
+use lucid::kvstore::{self, KvStore, Limits, CHUNK_SIZE};
 
 const CIPHER: std::option::Option<[&str; 2]> = Some([
     "123456789012345678901234123456789012345678901234",
@@ -11,7 +15,14 @@ const KEY: &str = "test_value";
 
 fn init_kv() -> KvStore {
     let kv = KvStore::new(CIPHER);
-    kv.set(KEY.to_string(), DATA.to_vec(), None);
+    kv.set(
+        KEY.to_string(),
+        vec![DATA.to_vec()],
+        None,
+        None,
+        &Limits::default(),
+    )
+    .unwrap();
     kv
 }
 
@@ -29,4 +40,594 @@ mod tests {
             None => panic!("No value found"),
         }
     }
+
+    #[test]
+    fn stats_track_stored_bytes() {
+        let kv = init_kv();
+        kv.set(
+            KEY.to_string(),
+            vec![b"short".to_vec()],
+            Some("text/plain".to_string()),
+            None,
+            &Limits {
+                owner: Some("alice"),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        kv.set(
+            "other".to_string(),
+            vec![b"{}".to_vec()],
+            Some("application/json".to_string()),
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+        let stats = kv.stats();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.bytes_by_mime["text/plain"], 5);
+        assert_eq!(stats.stored_bytes(), 7);
+        assert_eq!(kv.owner_bytes("alice"), 5);
+        assert_eq!(kv.entry_size(KEY), Some((5, Some("alice".to_string()))));
+
+        kv.drop(KEY.to_string());
+        assert_eq!(kv.stats().stored_bytes(), 2);
+
+        // Rejected writes are neither stored nor accounted
+        let check = |_: &kvstore::KvElement, replaced: Option<&kvstore::KvElement>| match replaced {
+            Some(_) => Err(kvstore::Error::QuotaExceeded { max_bytes: 2 }),
+            None => Ok(()),
+        };
+        let limits = Limits {
+            owner: Some("bob"),
+            check: &check,
+            ..Default::default()
+        };
+        assert!(matches!(
+            kv.set(
+                "other".to_string(),
+                vec![b"{ }".to_vec()],
+                None,
+                None,
+                &limits
+            ),
+            Err(kvstore::Error::QuotaExceeded { .. })
+        ));
+        assert_eq!(kv.get("other".to_string()).unwrap().data, &b"{}"[..]);
+        assert_eq!(kv.owner_bytes("bob"), 0);
+        assert_eq!(kv.stats().stored_bytes(), 2);
+    }
+
+    #[test]
+    fn evict_oldest_keys() {
+        let kv = KvStore::new(None);
+        for key in &["first", "second", "third"] {
+            kv.set(
+                key.to_string(),
+                vec![b"1234".to_vec()],
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap();
+        }
+        kv.switch_lock("first".to_string(), true);
+
+        assert_eq!(kv.evict(4, 10), 2);
+        assert!(kv.get("first".to_string()).is_some());
+        assert!(kv.get("second".to_string()).is_none());
+        assert!(kv.get("third".to_string()).is_none());
+    }
+
+    #[test]
+    fn expired_keys_are_removed() {
+        let kv = init_kv();
+        kv.set(
+            "expired".to_string(),
+            vec![b"1234".to_vec()],
+            None,
+            Some(Utc::now() - Duration::seconds(1)),
+            &Limits::default(),
+        )
+        .unwrap();
+        kv.set(
+            "alive".to_string(),
+            vec![b"1234".to_vec()],
+            None,
+            Some(Utc::now() + Duration::seconds(60)),
+            &Limits::default(),
+        )
+        .unwrap();
+
+        assert!(kv.get("expired".to_string()).is_none());
+        assert!(kv.get("alive".to_string()).is_some());
+        assert_eq!(kv.stats().keys, 2);
+        // Overwriting a value without an expiration clears its TTL
+        kv.set(
+            "alive".to_string(),
+            vec![b"1234".to_vec()],
+            None,
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+        assert_eq!(kv.get("alive".to_string()).unwrap().expire_at, None);
+    }
+
+    #[test]
+    fn increment_counters() {
+        let kv = init_kv();
+        let counter = || "counter".to_string();
+
+        assert!(matches!(
+            kv.increment(counter(), 1, None, &Limits::default()),
+            Err(kvstore::Error::KeyNotFound)
+        ));
+        assert_eq!(
+            kv.increment(counter(), 1, Some(10), &Limits::default())
+                .unwrap(),
+            10
+        );
+        assert_eq!(
+            kv.increment(counter(), -15, Some(10), &Limits::default())
+                .unwrap(),
+            -5
+        );
+        assert_eq!(kv.get(counter()).unwrap().data, b"-5".to_vec());
+        assert!(matches!(
+            kv.increment(counter(), i64::MIN, None, &Limits::default()),
+            Err(kvstore::Error::Overflow)
+        ));
+        assert!(matches!(
+            kv.increment(KEY.to_string(), 1, None, &Limits::default()),
+            Err(kvstore::Error::NotAnInteger)
+        ));
+    }
+
+    #[test]
+    fn list_operations() {
+        let kv = init_kv();
+        let list = || "queue".to_string();
+        let values = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+
+        assert_eq!(
+            kv.push(list(), values(&["b", "c"]), false, &Limits::default())
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            kv.push(list(), values(&["a"]), true, &Limits::default())
+                .unwrap(),
+            3
+        );
+        assert_eq!(kv.range(list(), 0, -1).unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(kv.range(list(), -2, 10).unwrap(), vec!["b", "c"]);
+        assert!(kv.range(list(), 2, 1).unwrap().is_empty());
+
+        assert_eq!(kv.pop(list(), false).unwrap(), "c");
+        assert_eq!(kv.trim(list(), 1, -1).unwrap(), 1);
+        assert_eq!(kv.pop(list(), true).unwrap(), "b");
+        // Emptied lists are removed
+        assert!(kv.get(list()).is_none());
+        assert!(matches!(
+            kv.pop(list(), true),
+            Err(kvstore::Error::KeyNotFound)
+        ));
+        assert!(matches!(
+            kv.push(KEY.to_string(), values(&["a"]), true, &Limits::default()),
+            Err(kvstore::Error::WrongType)
+        ));
+    }
+
+    #[test]
+    fn hash_operations() {
+        let kv = init_kv();
+        let hash = || "profile".to_string();
+        let mut fields = BTreeMap::new();
+        fields.insert("name".to_string(), "Alice".to_string());
+        fields.insert("visits".to_string(), "1".to_string());
+
+        assert_eq!(
+            kv.hset(hash(), fields.clone(), &Limits::default()).unwrap(),
+            2
+        );
+        assert_eq!(kv.hset(hash(), fields, &Limits::default()).unwrap(), 0);
+        assert_eq!(kv.hget(hash(), "name").unwrap(), "Alice");
+        assert_eq!(
+            kv.hincrby(hash(), "visits".to_string(), 41, &Limits::default())
+                .unwrap(),
+            42
+        );
+        let before = kv.get(hash()).unwrap();
+        assert!(matches!(
+            kv.hincrby(hash(), "name".to_string(), 1, &Limits::default()),
+            Err(kvstore::Error::NotAnInteger)
+        ));
+        // A failed update leaves the value as it was
+        let after = kv.get(hash()).unwrap();
+        assert_eq!(after.data, br#"{"name":"Alice","visits":"42"}"#.to_vec());
+        assert_eq!(after.update_count, before.update_count);
+        assert_eq!(after.updated_at, before.updated_at);
+
+        assert_eq!(kv.hdel(hash(), &["name".to_string()]).unwrap(), 1);
+        assert!(matches!(
+            kv.hget(hash(), "name"),
+            Err(kvstore::Error::FieldNotFound)
+        ));
+        kv.switch_lock(hash(), true);
+        assert!(matches!(
+            kv.hdel(hash(), &["visits".to_string()]),
+            Err(kvstore::Error::Locked)
+        ));
+    }
+
+    #[test]
+    fn set_operations() {
+        let kv = init_kv();
+        let members = |members: &[&str]| members.iter().map(|m| m.to_string()).collect();
+
+        assert_eq!(
+            kv.sadd(
+                "a".to_string(),
+                members(&["x", "y", "z"]),
+                &Limits::default()
+            )
+            .unwrap(),
+            3
+        );
+        assert_eq!(
+            kv.sadd("a".to_string(), members(&["x"]), &Limits::default())
+                .unwrap(),
+            0
+        );
+        kv.sadd(
+            "b".to_string(),
+            members(&["y", "z", "w"]),
+            &Limits::default(),
+        )
+        .unwrap();
+        assert!(kv.sismember("a".to_string(), "x").unwrap());
+        assert!(!kv.sismember("missing".to_string(), "x").unwrap());
+
+        let keys: Vec<String> = members(&["a", "b"]);
+        let intersection: Vec<String> = kv.sinter(&keys).unwrap().into_iter().collect();
+        assert_eq!(intersection, vec!["y", "z"]);
+        assert_eq!(kv.srem("a".to_string(), &members(&["x", "v"])).unwrap(), 1);
+    }
+
+    #[test]
+    fn sorted_set_operations() {
+        let kv = init_kv();
+        let board = || "leaderboard".to_string();
+        let mut scores = BTreeMap::new();
+        scores.insert("alice".to_string(), 30.0);
+        scores.insert("bob".to_string(), 10.0);
+        scores.insert("carol".to_string(), 20.0);
+
+        assert_eq!(kv.zadd(board(), scores, &Limits::default()).unwrap(), 3);
+        assert_eq!(
+            kv.zincrby(board(), "bob".to_string(), 25.0, &Limits::default())
+                .unwrap(),
+            35.0
+        );
+        let top: Vec<String> = kv
+            .zrange(board(), -2, -1)
+            .unwrap()
+            .into_iter()
+            .map(|(member, _)| member)
+            .collect();
+        assert_eq!(top, vec!["alice", "bob"]);
+        assert_eq!(
+            kv.zrange_by_score(board(), 20.0, 30.0).unwrap(),
+            vec![("carol".to_string(), 20.0), ("alice".to_string(), 30.0)]
+        );
+        assert_eq!(kv.zrem(board(), &["carol".to_string()]).unwrap(), 1);
+        assert!(matches!(
+            kv.zincrby(
+                board(),
+                "bob".to_string(),
+                f64::INFINITY,
+                &Limits::default()
+            ),
+            Err(kvstore::Error::NotANumber)
+        ));
+    }
+
+    #[test]
+    fn append_and_read_ranges() {
+        let kv = init_kv();
+        let log = || "log".to_string();
+
+        assert_eq!(kv.append(log(), b"hello", &Limits::default()).unwrap(), 5);
+        let nonce = kv.get(log()).unwrap().nonce;
+        assert_eq!(
+            kv.append(log(), b" encrypted world", &Limits::default())
+                .unwrap(),
+            21
+        );
+        assert_eq!(kv.get(log()).unwrap().data, &b"hello encrypted world"[..]);
+        // Encrypted values are sealed again instead of continuing the keystream
+        assert_ne!(kv.get(log()).unwrap().nonce, nonce);
+
+        let (value, len, range) = kv.get_range(log(), |_| Some(6..15)).unwrap();
+        assert_eq!(value.data, &b"encrypted"[..]);
+        assert_eq!(len, 21);
+        assert_eq!(range, Some(6..15));
+
+        let (_, _, range) = kv.get_range(log(), |len| Some(len..len + 1)).unwrap();
+        assert_eq!(range, None);
+        assert!(kv.get_range("missing".to_string(), |_| None).is_none());
+
+        kv.switch_lock(log(), true);
+        assert!(matches!(
+            kv.append(log(), b"!", &Limits::default()),
+            Err(kvstore::Error::Locked)
+        ));
+    }
+
+    #[test]
+    fn large_values_are_read_by_chunks() {
+        let value: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        for kv in &[KvStore::new(CIPHER), KvStore::new(None)] {
+            kv.set(
+                "large".to_string(),
+                vec![value.clone()],
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap();
+
+            let (element, chunks) = kv.get_chunks("large".to_string()).unwrap();
+            assert!(element.data.is_empty());
+            assert_eq!(chunks.size(), value.len());
+            let chunks: Vec<_> = chunks.collect();
+            assert_eq!(chunks.len(), 3);
+            assert_eq!(chunks[2].len(), 100);
+            assert_eq!(chunks.concat(), value);
+        }
+        assert!(init_kv().get_chunks("missing".to_string()).is_none());
+    }
+
+    #[test]
+    fn values_are_stored_by_chunks() {
+        let value: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let parts: Vec<Vec<u8>> = value.chunks(1000).map(<[u8]>::to_vec).collect();
+        for kv in &[KvStore::new(CIPHER), KvStore::new(None)] {
+            kv.set(
+                "large".to_string(),
+                parts.clone(),
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap();
+            kv.append("large".to_string(), b"tail", &Limits::default())
+                .unwrap();
+
+            let data = kv.get("large".to_string()).unwrap().data;
+            let sizes: Vec<_> = data.chunks().iter().map(|chunk| chunk.len()).collect();
+            assert_eq!(sizes, vec![CHUNK_SIZE, CHUNK_SIZE, 104]);
+            assert_eq!(data, [&value[..], b"tail"].concat());
+            let across = CHUNK_SIZE - 2..CHUNK_SIZE + 2;
+            assert_eq!(data.slice(across.clone()), value[across.clone()]);
+            let (partial, _, _) = kv
+                .get_range("large".to_string(), |_| Some(across.clone()))
+                .unwrap();
+            assert_eq!(partial.data, value[across].to_vec());
+        }
+    }
+
+    #[test]
+    fn values_are_compressed_before_encryption() {
+        let value = b"compressible ".repeat(1000);
+        for algorithm in &[CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4] {
+            let kv = KvStore::new(CIPHER).with_compression(Compression {
+                algorithm: *algorithm,
+                ..Default::default()
+            });
+            kv.set(
+                "large".to_string(),
+                vec![value.clone()],
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap();
+            kv.set(
+                "small".to_string(),
+                vec![b"tiny".to_vec()],
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap();
+
+            let stats = kv.stats().compression;
+            assert_eq!(stats.values, 1);
+            assert_eq!(stats.original_bytes, value.len() as u64);
+            assert!(stats.ratio().unwrap() > 10.0);
+            let (stored, _) = kv.entry_size("large").unwrap();
+            assert_eq!(stored, stats.stored_bytes);
+            assert_eq!(kv.entry_size("small").unwrap().0, 4);
+
+            assert_eq!(kv.get("large".to_string()).unwrap().data, value);
+            let (partial, len, _) = kv.get_range("large".to_string(), |_| Some(13..25)).unwrap();
+            assert_eq!(partial.data, &b"compressible"[..]);
+            assert_eq!(len, value.len());
+            let (_, chunks) = kv.get_chunks("large".to_string()).unwrap();
+            assert_eq!(chunks.size(), value.len());
+            assert_eq!(chunks.collect::<Vec<_>>().concat(), value);
+
+            assert_eq!(
+                kv.append("large".to_string(), b"!", &Limits::default())
+                    .unwrap(),
+                value.len() + 1
+            );
+            assert_eq!(
+                kv.get("large".to_string()).unwrap().data.len(),
+                value.len() + 1
+            );
+            kv.drop("large".to_string());
+            assert_eq!(kv.stats().compression.stored_bytes, 0);
+        }
+    }
+
+    #[test]
+    fn version_history() {
+        let kv = KvStore::new(CIPHER).with_history(History {
+            max_versions: 2,
+            ..Default::default()
+        });
+        let doc = || "doc".to_string();
+        for value in &["v1", "v2", "v3", "v4"] {
+            kv.set(
+                doc(),
+                vec![value.as_bytes().to_vec()],
+                None,
+                None,
+                &Limits::default(),
+            )
+            .unwrap();
+        }
+
+        let versions: Vec<i32> = kv
+            .history(doc())
+            .unwrap()
+            .iter()
+            .map(|version| version.update_count)
+            .collect();
+        assert_eq!(versions, vec![4, 3, 2]);
+        assert_eq!(kv.get_version(doc(), 2).unwrap().data, &b"v2"[..]);
+        assert!(matches!(
+            kv.get_version(doc(), 1),
+            Err(kvstore::Error::VersionNotFound)
+        ));
+
+        assert_eq!(kv.restore(doc(), 2).unwrap(), 5);
+        assert_eq!(kv.get(doc()).unwrap().data, &b"v2"[..]);
+        assert_eq!(kv.get_version(doc(), 4).unwrap().data, &b"v4"[..]);
+        kv.switch_lock(doc(), true);
+        assert!(matches!(kv.restore(doc(), 4), Err(kvstore::Error::Locked)));
+
+        kv.drop(doc());
+        assert!(matches!(
+            kv.history(doc()),
+            Err(kvstore::Error::KeyNotFound)
+        ));
+        kv.set(doc(), vec![b"v1".to_vec()], None, None, &Limits::default())
+            .unwrap();
+        assert_eq!(kv.history(doc()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn restored_values_get_a_new_nonce() {
+        let kv = KvStore::new(CIPHER).with_history(History {
+            max_versions: 5,
+            ..Default::default()
+        });
+        let log = || "log".to_string();
+        kv.set(
+            log(),
+            vec![b"start".to_vec()],
+            None,
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+        kv.append(log(), b" one", &Limits::default()).unwrap();
+        let appended = kv.get(log()).unwrap().nonce;
+
+        kv.restore(log(), 1).unwrap();
+        let restored = kv.get(log()).unwrap();
+        assert_eq!(restored.data, &b"start"[..]);
+        assert_ne!(restored.nonce, kv.get_version(log(), 1).unwrap().nonce);
+        assert_ne!(restored.nonce, appended);
+
+        kv.append(log(), b" two", &Limits::default()).unwrap();
+        assert_eq!(kv.get(log()).unwrap().data, &b"start two"[..]);
+        assert_eq!(kv.get_version(log(), 2).unwrap().data, &b"start one"[..]);
+    }
+
+    #[test]
+    fn trash_and_undelete() {
+        let kv = init_kv();
+        let retention = Duration::seconds(60);
+        kv.set(
+            "a/1".to_string(),
+            vec![b"one".to_vec()],
+            None,
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+        kv.set(
+            "a/2".to_string(),
+            vec![b"two".to_vec()],
+            None,
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+        assert!(kv.trash("a/1".to_string(), retention));
+        assert!(!kv.trash("a/1".to_string(), retention));
+        assert!(kv.get("a/1".to_string()).is_none());
+        assert_eq!(kv.stats().keys, 2);
+        let trashed = kv.trashed("a/");
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].0, "a/1");
+        assert_eq!(trashed[0].1.kv_element.size, 3);
+
+        kv.undelete("a/1".to_string(), &Limits::default()).unwrap();
+        assert_eq!(kv.get("a/1".to_string()).unwrap().data, &b"one"[..]);
+        assert!(matches!(
+            kv.undelete("a/1".to_string(), &Limits::default()),
+            Err(kvstore::Error::KeyNotFound)
+        ));
+
+        kv.trash("a/1".to_string(), retention);
+        kv.set(
+            "a/1".to_string(),
+            vec![b"new".to_vec()],
+            None,
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            kv.undelete("a/1".to_string(), &Limits::default()),
+            Err(kvstore::Error::KeyExists)
+        ));
+        assert!(kv.purge("a/1"));
+        assert!(!kv.purge("a/1"));
+
+        assert_eq!(kv.trash_prefix("a/", retention), vec!["a/1", "a/2"]);
+        // Trashed values are not counted, restoring one checks the limits again
+        let check = |_: &kvstore::KvElement, _: Option<&kvstore::KvElement>| {
+            Err(kvstore::Error::QuotaExceeded { max_bytes: 2 })
+        };
+        let full = Limits {
+            check: &check,
+            ..Default::default()
+        };
+        assert!(matches!(
+            kv.undelete("a/2".to_string(), &full),
+            Err(kvstore::Error::QuotaExceeded { .. })
+        ));
+        assert!(kv.get("a/2".to_string()).is_none());
+        assert!(!kv.trash("missing".to_string(), retention));
+        kv.set(
+            "b".to_string(),
+            vec![b"b".to_vec()],
+            None,
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+        kv.trash("b".to_string(), Duration::seconds(-1));
+        assert_eq!(kv.purge_prefix(""), vec!["a/1", "a/2"]);
+        assert!(kv.trashed("").is_empty());
+    }
 }
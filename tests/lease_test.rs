@@ -0,0 +1,73 @@
+use chrono::Duration;
+
+use lucid::lease::{Acquire, Error, Leases};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_owner_renews_and_releases() {
+        let leases = Leases::new();
+        let lease = leases.acquire("jobs", Duration::seconds(30)).unwrap();
+        assert!(matches!(
+            leases.acquire("jobs", Duration::seconds(30)),
+            Err(Error::Held { .. })
+        ));
+        assert!(matches!(
+            leases.release("jobs", "other"),
+            Err(Error::NotOwner)
+        ));
+
+        let renewed = leases
+            .renew("jobs", &lease.token, Duration::seconds(60))
+            .unwrap();
+        assert_eq!(renewed.fencing_token, lease.fencing_token);
+        assert!(renewed.expire_at > lease.expire_at);
+
+        leases.release("jobs", &lease.token).unwrap();
+        assert!(leases.get("jobs").is_none());
+        assert!(matches!(
+            leases.release("jobs", &lease.token),
+            Err(Error::NotHeld)
+        ));
+    }
+
+    #[test]
+    fn expired_leases_are_handed_over_in_order() {
+        let leases = Leases::new();
+        let first = leases.acquire("jobs", Duration::milliseconds(-1)).unwrap();
+        let second = leases.acquire("jobs", Duration::seconds(30)).unwrap();
+        assert!(second.fencing_token > first.fencing_token);
+        assert!(matches!(
+            leases.check_fencing_token("jobs", first.fencing_token),
+            Err(Error::Stale { .. })
+        ));
+        assert!(leases
+            .check_fencing_token("jobs", second.fencing_token)
+            .is_ok());
+        assert!(matches!(
+            leases.renew("jobs", &first.token, Duration::seconds(30)),
+            Err(Error::NotOwner)
+        ));
+
+        let mut queued = Vec::new();
+        for _ in 0..3 {
+            match leases.acquire_or_queue("jobs", Duration::seconds(30)) {
+                Acquire::Queued { id, receiver } => queued.push((id, receiver)),
+                Acquire::Acquired(_) => panic!("the lock is held"),
+            }
+        }
+        assert_eq!(leases.get("jobs").unwrap().1, 3);
+
+        let (id, _) = queued.remove(0);
+        assert!(leases.cancel("jobs", id));
+        leases.release("jobs", &second.token).unwrap();
+
+        let (id, mut receiver) = queued.remove(0);
+        let third = receiver.try_recv().unwrap();
+        assert!(third.fencing_token > second.fencing_token);
+        assert!(!leases.cancel("jobs", id));
+        assert_eq!(leases.get("jobs").unwrap().1, 1);
+    }
+}
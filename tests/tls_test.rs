@@ -0,0 +1,114 @@
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use lucid::{configuration::General, tls::TlsConfig};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+struct Certificate {
+    certificate: PathBuf,
+    key: PathBuf,
+}
+
+impl Certificate {
+    fn new(name: &str) -> Certificate {
+        let path = |extension: &str| {
+            std::env::temp_dir().join(format!(
+                "lucid-{}-{}.{}",
+                name,
+                std::process::id(),
+                extension
+            ))
+        };
+        let certificate = Certificate {
+            certificate: path("crt"),
+            key: path("key"),
+        };
+        certificate.install("first");
+        certificate
+    }
+
+    fn install(&self, fixture: &str) {
+        fs::copy(format!("{}/{}.crt", FIXTURES, fixture), &self.certificate).unwrap();
+        fs::copy(format!("{}/{}.key", FIXTURES, fixture), &self.key).unwrap();
+    }
+
+    fn general(&self) -> General {
+        General {
+            ssl_certificate: self.certificate.to_string_lossy().to_string(),
+            ssl_certificate_key: self.key.to_string_lossy().to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_picks_up_new_certificate() {
+        let certificate = Certificate::new("reload");
+        let tls = TlsConfig::new(&certificate.general()).unwrap();
+        let first = tls.current();
+
+        certificate.install("second");
+        tls.reload().unwrap();
+        let second = tls.current();
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        fs::write(&certificate.key, "not a key").unwrap();
+        assert!(tls.reload().is_err());
+        assert!(Arc::ptr_eq(&second, &tls.current()));
+    }
+
+    #[test]
+    fn client_ca_needs_a_certificate() {
+        let certificate = Certificate::new("client-ca");
+        let general = General {
+            ssl_client_ca: certificate.certificate.to_string_lossy().to_string(),
+            ..certificate.general()
+        };
+        assert!(TlsConfig::new(&general).is_ok());
+
+        let empty = std::env::temp_dir().join(format!("lucid-empty-{}.crt", std::process::id()));
+        fs::write(&empty, "no certificate").unwrap();
+        let general = General {
+            ssl_client_ca: empty.to_string_lossy().to_string(),
+            ..certificate.general()
+        };
+        assert!(TlsConfig::new(&general).is_err());
+    }
+
+    #[tokio::test]
+    async fn watched_files_are_reloaded() {
+        let certificate = Certificate::new("watch");
+        let tls = TlsConfig::new(&certificate.general()).unwrap();
+        let first = tls.current();
+        tokio::spawn(tls.clone().watch_files(Duration::from_millis(10)));
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        // Some filesystems only keep the modification time to the second
+        let touch = |offset: u64| {
+            File::options()
+                .write(true)
+                .open(&certificate.key)
+                .unwrap()
+                .set_modified(SystemTime::now() + Duration::from_secs(offset))
+                .unwrap();
+        };
+        fs::write(&certificate.key, "not a key").unwrap();
+        touch(5);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(Arc::ptr_eq(&first, &tls.current()));
+
+        certificate.install("second");
+        touch(10);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(!Arc::ptr_eq(&first, &tls.current()));
+    }
+}